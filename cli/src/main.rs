@@ -2,13 +2,21 @@
 extern crate lazy_static;
 use clap::Parser;
 use directories::ProjectDirs;
+use pie_lib::config::{get_cli_config, get_cli_config_path, CliConfig};
 mod pie;
 
 #[derive(Parser, Debug)]
 #[clap(version = env!("CARGO_PKG_VERSION"), author = "beni69 <beni@karesz.xyz>")]
 struct Cli {
-    #[clap(short, long, default_value = "http://127.0.0.1:6969")]
-    url: String,
+    /// the server to use, by its name in the cli config. defaults to the default server
+    #[clap(short, long)]
+    server: Option<String>,
+    /// use this url instead of the one of the server. defaults to a local server without a config
+    #[clap(short, long)]
+    url: Option<String>,
+    /// use this api token instead of the one of the server
+    #[clap(long)]
+    token: Option<String>,
     #[clap(subcommand)]
     subcmd: SubCommand,
 }
@@ -16,19 +24,155 @@ struct Cli {
 enum SubCommand {
     Greet,
     Ping,
+    Login(Login),
+    Server(Server),
     Deploy(Deploy),
+    /// list the apps deployed on the server
+    Apps,
+    /// show how an app was deployed, and the status of its processes
+    Info(AppArg),
+    /// start an app that was stopped
+    Start(AppArg),
+    /// stop an app. pushes and restarts of the server don't start it again, until it's started or deployed
+    Stop(AppArg),
+    /// stop an app and start it again
+    Restart(AppArg),
+    Logs(Logs),
+    Destroy(Destroy),
+    Secrets(Secrets),
+}
+/// log in to a server with an api token from its config, and make it the default server
+#[derive(Parser, Debug)]
+pub struct Login {
+    url: String,
+    /// the name to save the server as. defaults to the host of the url
+    #[clap(short, long)]
+    name: Option<String>,
+    /// the api token. asked for when not given
+    #[clap(short, long)]
+    token: Option<String>,
+}
+
+/// manage the servers saved in the cli config
+#[derive(Parser, Debug)]
+pub struct Server {
+    #[clap(subcommand)]
+    action: ServerAction,
+}
+#[derive(Parser, Debug)]
+pub enum ServerAction {
+    /// list the servers, marking the default one
+    List,
+    /// add a server, without checking the token like `pie login` does
+    Add {
+        name: String,
+        url: String,
+        #[clap(short, long)]
+        token: Option<String>,
+        /// make it the default server
+        #[clap(short, long)]
+        default: bool,
+    },
+    Remove {
+        name: String,
+    },
+    /// make a server the default
+    Default {
+        name: String,
+    },
 }
-#[derive(Parser, Clone, Copy, Debug)]
+
+/// deploy the app of the current directory, from its git remote
+#[derive(Parser, Debug)]
 pub struct Deploy {
+    /// upload the current directory instead, leaving out what's in .gitignore and .pieignore
     #[clap(short, long)]
     offline: bool,
+    /// the app to deploy the upload as, as `owner/name`.
+    /// defaults to the git remote of the current directory, or `local/<directory name>`
+    #[clap(short, long, requires = "offline")]
+    app: Option<String>,
+    /// the branch, tag or commit to deploy. defaults to the default branch of the repo
+    #[clap(short, long = "ref", conflicts_with = "offline")]
+    git_ref: Option<String>,
+    /// how many commits of history the server clones. 0 is the full history,
+    /// defaults to the `depth` of the server config
+    #[clap(long, conflicts_with = "offline")]
+    depth: Option<u32>,
+    /// redeploy from a fresh clone, if the app was already deployed
+    #[clap(short, long)]
+    force: bool,
+}
+
+#[derive(Parser, Debug)]
+pub struct AppArg {
+    /// the app, as `owner/name`. defaults to the git remote of the current directory
+    app: Option<String>,
+}
+
+/// print the logs of an app
+#[derive(Parser, Debug)]
+pub struct Logs {
+    /// the app, as `owner/name`. defaults to the git remote of the current directory
+    app: Option<String>,
+    /// keep printing the lines written to the log
+    #[clap(short, long)]
+    follow: bool,
+    /// how many of the last lines to print. defaults to 100
+    #[clap(short = 'n', long)]
+    tail: Option<usize>,
+    /// the process to print the logs of. defaults to `web`
+    #[clap(short, long)]
+    process: Option<String>,
+}
+
+/// stop an app and remove it from the server, along with its webhook, clone and logs.
+/// its secrets are kept
+#[derive(Parser, Debug)]
+pub struct Destroy {
+    /// the app, as `owner/name`. defaults to the git remote of the current directory
+    app: Option<String>,
+    /// don't ask for confirmation
+    #[clap(short, long)]
+    yes: bool,
+}
+
+/// manage the secrets of an app, which are passed to it as env variables
+#[derive(Parser, Debug)]
+pub struct Secrets {
+    /// the app, as `owner/name`. defaults to the git remote of the current directory
+    #[clap(short, long)]
+    app: Option<String>,
+    #[clap(subcommand)]
+    action: SecretsAction,
+}
+#[derive(Parser, Debug)]
+pub enum SecretsAction {
+    /// list the secrets of the app, with their values masked
+    List,
+    Set {
+        key: String,
+        value: String,
+    },
+    Unset {
+        key: String,
+    },
 }
 
 lazy_static! {
     static ref PROJECT_DIRS: ProjectDirs = ProjectDirs::from("", "beni69", "pie").unwrap();
     static ref CLI: Cli = Cli::parse();
+    static ref CONFIG: CliConfig = get_cli_config().unwrap_or_else(|e| {
+        eprintln!(
+            "Invalid cli config file at {:?}: {}",
+            get_cli_config_path(),
+            e
+        );
+        std::process::exit(1);
+    });
 }
 
+#[allow(dead_code)]
 #[derive(Debug)]
 enum MainError {
     SurfError(surf::Error),
@@ -41,10 +185,20 @@ impl From<surf::Error> for MainError {
 
 #[async_std::main]
 async fn main() -> Result<(), MainError> {
-    match CLI.subcmd {
+    match &CLI.subcmd {
         SubCommand::Greet => println!("Hello, world!"),
         SubCommand::Ping => pie::ping().await?,
+        SubCommand::Login(opts) => pie::login(opts).await?,
+        SubCommand::Server(opts) => pie::server(opts),
         SubCommand::Deploy(opts) => pie::deploy(opts).await?,
+        SubCommand::Apps => pie::apps().await?,
+        SubCommand::Info(opts) => pie::info(opts).await?,
+        SubCommand::Start(opts) => pie::control(opts, "start").await?,
+        SubCommand::Stop(opts) => pie::control(opts, "stop").await?,
+        SubCommand::Restart(opts) => pie::control(opts, "restart").await?,
+        SubCommand::Logs(opts) => pie::logs(opts).await?,
+        SubCommand::Destroy(opts) => pie::destroy(opts).await?,
+        SubCommand::Secrets(opts) => pie::secrets(opts).await?,
     }
 
     Ok(())
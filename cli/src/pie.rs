@@ -1,13 +1,82 @@
-use crate::{Deploy, CLI};
-use pie_lib::utils::exec_sync;
-use std::process::exit;
-use surf::{Client, Error, Url};
+use crate::{
+    AppArg, Deploy, Destroy, Login, Logs, Secrets, SecretsAction, Server, ServerAction, CLI, CONFIG,
+};
+use async_std::{
+    io::{prelude::*, BufReader, Lines},
+    stream::StreamExt,
+};
+use flate2::{write::GzEncoder, Compression};
+use ignore::WalkBuilder;
+use pie_lib::{
+    api::{
+        ApiError, App, AppInfo, AppStatus, DeployParams, DeployStatus, LogsParams, ProcessStatus,
+        UploadParams,
+    },
+    config::{get_cli_config_path, save_cli_config, CliConfig, ServerProfile},
+    utils::{
+        exec_sync, get_unix_time, is_valid_repo_part, normalize_git_url, split_repo, url_to_repo,
+    },
+};
+use std::{collections::BTreeMap, io::Write, path::Path, process::exit};
+use surf::{Client, Error, Response, StatusCode, Url};
+
+const DEFAULT_SERVER_URL: &str = "http://127.0.0.1:6969";
 
 lazy_static! {
-    static ref CLIENT: Client = surf::Config::new()
-        .set_base_url(Url::parse(&CLI.url).unwrap())
-        .try_into()
-        .unwrap();
+    static ref CLIENT: Client = {
+        let server = match &CLI.server {
+            Some(name) => match CONFIG.server(Some(name)) {
+                Some((_, server)) => Some(server),
+                None => {
+                    eprintln!("No server named {} in the cli config.\nAdd it with `pie login` or `pie server add`.", name);
+                    exit(1);
+                }
+            },
+            None => CONFIG.server(None).map(|(_, server)| server),
+        };
+
+        let url = CLI
+            .url
+            .as_deref()
+            .or_else(|| server.map(|s| s.url.as_str()))
+            .unwrap_or(DEFAULT_SERVER_URL);
+        let token = CLI
+            .token
+            .as_deref()
+            .or_else(|| server.and_then(|s| s.token.as_deref()));
+
+        make_client(url, token)
+    };
+}
+
+fn make_client(url: &str, token: Option<&str>) -> Client {
+    // deploys take as long as the build
+    let mut config =
+        surf::Config::new()
+            .set_timeout(None)
+            .set_base_url(Url::parse(url).unwrap_or_else(|e| {
+                eprintln!("Invalid server url {}: {}", url, e);
+                exit(1);
+            }));
+
+    if let Some(token) = token {
+        config = config
+            .add_header("Authorization", format!("Bearer {}", token))
+            .unwrap();
+    }
+
+    config.try_into().unwrap()
+}
+
+fn save_config(config: &CliConfig) {
+    if let Err(e) = save_cli_config(config) {
+        eprintln!(
+            "Unable to save the cli config to {:?}: {}",
+            get_cli_config_path(),
+            e
+        );
+        exit(1);
+    }
 }
 
 pub async fn ping() -> Result<(), Error> {
@@ -18,22 +87,560 @@ pub async fn ping() -> Result<(), Error> {
     Ok(())
 }
 
-pub async fn deploy(opts: Deploy) -> Result<(), Error> {
-    println!("{:?}", opts);
+pub async fn login(opts: &Login) -> Result<(), Error> {
+    let name = match &opts.name {
+        Some(name) => name.clone(),
+        None => match Url::parse(&opts.url)
+            .ok()
+            .and_then(|u| u.host_str().map(String::from))
+        {
+            Some(host) => host,
+            None => {
+                eprintln!("Invalid server url {}", &opts.url);
+                exit(1);
+            }
+        },
+    };
+
+    let token = match &opts.token {
+        Some(token) => token.clone(),
+        None => {
+            print!("Api token (from the `tokens` in the server's pie-server.toml): ");
+            std::io::stdout().flush()?;
+            let mut token = String::new();
+            std::io::stdin().read_line(&mut token)?;
+            token.trim().to_string()
+        }
+    };
+
+    // check the token before saving it
+    let mut res = make_client(&opts.url, Some(&token)).get("/auth").await?;
+    check_response(&mut res).await?;
 
-    let remote_url = match exec_sync(
+    let mut config = CONFIG.clone();
+    config.servers.insert(
+        name.clone(),
+        ServerProfile {
+            url: opts.url.clone(),
+            token: Some(token),
+        },
+    );
+    config.default = Some(name.clone());
+    save_config(&config);
+
+    println!(
+        "Logged in to {}, saved as the default server {}",
+        &opts.url, &name
+    );
+    Ok(())
+}
+
+pub fn server(opts: &Server) {
+    let mut config = CONFIG.clone();
+
+    match &opts.action {
+        ServerAction::List => {
+            if config.servers.is_empty() {
+                println!("No servers saved yet, add one with `pie login`");
+            }
+            let default = config.server(None).map(|(name, _)| name);
+            for (name, server) in &config.servers {
+                let mark = if Some(name) == default { "*" } else { " " };
+                println!("{} {} {}", mark, name, server.url);
+            }
+            return;
+        }
+        ServerAction::Add {
+            name,
+            url,
+            token,
+            default,
+        } => {
+            if let Err(e) = Url::parse(url) {
+                eprintln!("Invalid server url {}: {}", url, e);
+                exit(1);
+            }
+            config.servers.insert(
+                name.clone(),
+                ServerProfile {
+                    url: url.clone(),
+                    token: token.clone(),
+                },
+            );
+            if *default || config.default.is_none() {
+                config.default = Some(name.clone());
+            }
+            println!("Added server {}", name);
+        }
+        ServerAction::Remove { name } => {
+            if config.servers.remove(name).is_none() {
+                eprintln!("No server named {}", name);
+                exit(1);
+            }
+            if config.default.as_ref() == Some(name) {
+                config.default = None;
+            }
+            println!("Removed server {}", name);
+        }
+        ServerAction::Default { name } => {
+            if !config.servers.contains_key(name) {
+                eprintln!("No server named {}", name);
+                exit(1);
+            }
+            config.default = Some(name.clone());
+            println!("{} is now the default server", name);
+        }
+    }
+
+    save_config(&config);
+}
+
+fn get_remote_url() -> String {
+    match exec_sync(
         "git config --get remote.origin.url",
         std::env::current_dir().unwrap(),
     ) {
         Ok(x) => x.trim().to_string(),
-        Err(_) => todo!(),
-    };
-    dbg!(&remote_url);
+        Err(_) => String::new(),
+    }
+}
 
-    if remote_url.len() == 0 {
+/// the app given on the command line, or the one of the current directory
+fn get_app(app: &Option<String>) -> String {
+    if let Some(app) = app {
+        let valid = app
+            .split_once('/')
+            .is_some_and(|(owner, name)| is_valid_repo_part(owner) && is_valid_repo_part(name));
+        if !valid {
+            eprintln!("The app has to be given as `owner/name`");
+            exit(1);
+        }
+        return app.clone();
+    }
+
+    match url_to_repo(&get_remote_url()) {
+        Some(app) => app,
+        None => {
+            eprintln!("Unable to tell which app to use from the git remote of the current directory.\nRun again with the app given as `owner/name`.");
+            exit(1);
+        }
+    }
+}
+
+/// print the error of a failed request and exit
+async fn check_response(res: &mut Response) -> Result<(), Error> {
+    if res.status().is_success() {
+        return Ok(());
+    }
+
+    let body = res.body_string().await?;
+    print_api_error(&body, Some(res.status()));
+    exit(1);
+}
+
+fn print_api_error(body: &str, status: Option<StatusCode>) {
+    match serde_json::from_str::<ApiError>(body) {
+        Ok(err) => {
+            eprintln!("error: {} ({})", err.message, err.code);
+            if let Some(details) = err.details {
+                eprintln!("{}", details);
+            }
+        }
+        // not one of our errors, probably a proxy in the way
+        Err(_) => match status {
+            Some(status) => eprintln!("error: {} {}", status, body),
+            None => eprintln!("error: {}", body),
+        },
+    }
+}
+
+pub async fn deploy(opts: &Deploy) -> Result<(), Error> {
+    if opts.offline {
+        return deploy_offline(opts).await;
+    }
+
+    let remote_url = get_remote_url();
+    if remote_url.is_empty() {
         eprintln!("No origin git remote found.\nIf your project is using git be sure to run the command in the correct directory.\nIf your project does not use git, run again with the --offline option.");
         exit(1);
     }
+    let (app, url) = match url_to_repo(&remote_url).zip(normalize_git_url(&remote_url)) {
+        Some(x) => x,
+        None => {
+            eprintln!("Unable to parse the origin git remote {}", &remote_url);
+            exit(1);
+        }
+    };
+
+    let mut res = CLIENT
+        .post("/deploy")
+        .body_json(&DeployParams {
+            repo: url,
+            git_ref: opts.git_ref.clone(),
+            depth: opts.depth,
+            force: Some(opts.force),
+        })?
+        .await?;
+    check_response(&mut res).await?;
+    println!("Deploying {}", &app);
+
+    follow_deploy(res, &app).await
+}
+
+async fn deploy_offline(opts: &Deploy) -> Result<(), Error> {
+    let dir = std::env::current_dir()?;
+    let app = match &opts.app {
+        Some(app) => app.clone(),
+        None => url_to_repo(&get_remote_url()).unwrap_or_else(|| {
+            let name = dir
+                .file_name()
+                .map_or("app".into(), |n| n.to_string_lossy());
+            format!("local/{}", name)
+        }),
+    };
+    let (owner, name) = match app.split_once('/') {
+        Some(x) => x,
+        None => {
+            eprintln!("The app has to be given as `owner/name`");
+            exit(1);
+        }
+    };
+
+    let (archive, files) = pack_dir(&dir)?;
+    println!(
+        "Uploading {} files ({} KiB) as {}",
+        files,
+        archive.len() / 1024,
+        &app
+    );
+
+    let mut res = CLIENT
+        .post(format!("/upload/{}/{}", owner, name))
+        .query(&UploadParams {
+            force: Some(opts.force),
+        })?
+        .content_type("application/gzip")
+        .body(archive)
+        .await?;
+    check_response(&mut res).await?;
+
+    follow_deploy(res, &app).await
+}
+
+/// a gzipped tarball of a directory, and the number of files in it.
+/// whatever git would ignore is left out, and so is anything in a `.pieignore`
+fn pack_dir(dir: &Path) -> Result<(Vec<u8>, usize), std::io::Error> {
+    let mut tar = tar::Builder::new(GzEncoder::new(Vec::new(), Compression::default()));
+    tar.follow_symlinks(false);
+    let mut files = 0;
+
+    let walker = WalkBuilder::new(dir)
+        .hidden(false)
+        .require_git(false)
+        .add_custom_ignore_filename(".pieignore")
+        .filter_entry(|e| e.file_name() != ".git")
+        .build();
+    for entry in walker {
+        let entry = entry.map_err(std::io::Error::other)?;
+        let path = entry.path();
+        let rel = path.strip_prefix(dir).unwrap();
+        if rel.as_os_str().is_empty() {
+            continue;
+        }
+
+        if entry.file_type().is_some_and(|t| t.is_dir()) {
+            tar.append_dir(rel, path)?;
+        } else {
+            tar.append_path_with_name(path, rel)?;
+            files += 1;
+        }
+    }
+
+    Ok((tar.into_inner()?.finish()?, files))
+}
+
+/// print the events of a deploy until it's done, exiting if it fails
+async fn follow_deploy(res: Response, app: &str) -> Result<(), Error> {
+    let mut events = Events::new(res);
+    while let Some((event, data)) = events.next().await? {
+        match event.as_str() {
+            "progress" => println!("  {}", data),
+            "done" => {
+                let status: AppStatus = serde_json::from_str(&data)?;
+                println!("Deployed {}", app);
+                print_status(&status);
+                return Ok(());
+            }
+            "error" => {
+                print_api_error(&data, None);
+                exit(1);
+            }
+            _ => {}
+        }
+    }
+
+    eprintln!("error: the server closed the connection before the deploy finished");
+    exit(1);
+}
+
+fn print_status(status: &AppStatus) {
+    for (name, process) in status {
+        match process {
+            ProcessStatus::Running {
+                pid,
+                cmd,
+                uptime,
+                restarts,
+            } => println!(
+                "{}: running (pid {}, up {}s, {} restarts) `{}`",
+                name, pid, uptime, restarts, cmd
+            ),
+            ProcessStatus::Exited {
+                pid,
+                cmd,
+                code,
+                uptime,
+                restarts,
+            } => println!(
+                "{}: exited with {} (pid {}, ran {}s, {} restarts) `{}`",
+                name,
+                code.map_or("a signal".into(), |c| format!("code {}", c)),
+                pid,
+                uptime,
+                restarts,
+                cmd
+            ),
+        }
+    }
+}
+
+/// reads a server-sent events response, one `(event, data)` at a time
+struct Events {
+    lines: Lines<BufReader<Response>>,
+}
+
+impl Events {
+    fn new(res: Response) -> Self {
+        Self {
+            lines: BufReader::new(res).lines(),
+        }
+    }
+
+    async fn next(&mut self) -> Result<Option<(String, String)>, Error> {
+        let mut event = String::new();
+        let mut data = Vec::new();
+
+        while let Some(line) = self.lines.next().await {
+            let line = line?;
+            if line.is_empty() {
+                if !data.is_empty() {
+                    return Ok(Some((event, data.join("\n"))));
+                }
+            } else if let Some(e) = line.strip_prefix("event:") {
+                event = e.trim().to_string();
+            } else if let Some(d) = line.strip_prefix("data:") {
+                data.push(d.strip_prefix(' ').unwrap_or(d).to_string());
+            }
+        }
+
+        Ok(None)
+    }
+}
+
+pub async fn apps() -> Result<(), Error> {
+    let mut res = CLIENT.get("/apps").await?;
+    check_response(&mut res).await?;
+
+    let apps: Vec<AppInfo> = res.body_json().await?;
+    if apps.is_empty() {
+        println!("No apps deployed yet, deploy one with `pie deploy`");
+    }
+    for info in apps {
+        let running = info
+            .process_status
+            .values()
+            .filter(|p| matches!(p, ProcessStatus::Running { .. }))
+            .count();
+        println!(
+            "{:<30} {:<9} {:<24} {}/{} running{}",
+            &info.app.repo,
+            deploy_status(&info.app),
+            source(&info.app),
+            running,
+            info.app.processes.len(),
+            if info.app.stopped { " (stopped)" } else { "" }
+        );
+    }
+
+    Ok(())
+}
+
+pub async fn info(opts: &AppArg) -> Result<(), Error> {
+    let app = get_app(&opts.app);
+    let (owner, name) = split_repo(&app);
+
+    let mut res = CLIENT.get(format!("/apps/{}/{}", owner, name)).await?;
+    check_response(&mut res).await?;
+    let info: AppInfo = res.body_json().await?;
+    let app = &info.app;
+
+    println!("{}", &app.repo);
+    println!("  {} {}", deploy_status(app), ago(app.deployed_at));
+    if let Some(error) = &app.error {
+        println!("  error: {}", error);
+    }
+    if app.stopped {
+        println!("  stopped, start it with `pie start`");
+    }
+    match &app.url {
+        Some(url) => println!("  from {} at {}", url, source(app)),
+        None => println!("  from an upload"),
+    }
+    if let Some(depth) = app.depth.filter(|d| *d > 0) {
+        println!("  cloned with a depth of {}", depth);
+    }
+
+    if info.process_status.is_empty() {
+        println!("not running");
+    }
+    print_status(&info.process_status);
+
+    Ok(())
+}
+
+/// start, stop or restart an app, as the action of its `/apps/:owner/:name/:action` route
+pub async fn control(opts: &AppArg, action: &str) -> Result<(), Error> {
+    let app = get_app(&opts.app);
+    let (owner, name) = split_repo(&app);
+
+    let mut res = CLIENT
+        .post(format!("/apps/{}/{}/{}", owner, name, action))
+        .await?;
+    check_response(&mut res).await?;
+    let status: AppStatus = res.body_json().await?;
+
+    let done = match action {
+        "start" => "Started",
+        "stop" => "Stopped",
+        _ => "Restarted",
+    };
+    println!("{} {}", done, &app);
+    print_status(&status);
+
+    Ok(())
+}
+
+pub async fn logs(opts: &Logs) -> Result<(), Error> {
+    let app = get_app(&opts.app);
+    let (owner, name) = split_repo(&app);
+
+    let mut res = CLIENT
+        .get(format!("/apps/{}/{}/logs", owner, name))
+        .query(&LogsParams {
+            tail: opts.tail,
+            follow: Some(opts.follow),
+            process: opts.process.clone(),
+        })?
+        .await?;
+    check_response(&mut res).await?;
+
+    let mut events = Events::new(res);
+    while let Some((event, data)) = events.next().await? {
+        if event == "log" {
+            println!("{}", data);
+        }
+    }
+
+    Ok(())
+}
+
+pub async fn destroy(opts: &Destroy) -> Result<(), Error> {
+    let app = get_app(&opts.app);
+    let (owner, name) = split_repo(&app);
+
+    if !opts.yes {
+        print!(
+            "Destroy {}? Its webhook, clone and logs are removed from the server [y/N] ",
+            &app
+        );
+        std::io::stdout().flush()?;
+        let mut answer = String::new();
+        std::io::stdin().read_line(&mut answer)?;
+        if !matches!(answer.trim(), "y" | "Y" | "yes") {
+            println!("Cancelled");
+            return Ok(());
+        }
+    }
+
+    let mut res = CLIENT.delete(format!("/apps/{}/{}", owner, name)).await?;
+    check_response(&mut res).await?;
+    println!("{}", res.body_string().await?);
+
+    Ok(())
+}
+
+fn deploy_status(app: &App) -> &'static str {
+    match app.status {
+        DeployStatus::Deploying => "deploying",
+        DeployStatus::Deployed => "deployed",
+        DeployStatus::Failed => "failed",
+    }
+}
+
+/// the ref and short commit an app was deployed from
+fn source(app: &App) -> String {
+    let commit = app.commit.as_deref().map(|c| &c[..c.len().min(7)]);
+    match (&app.url, app.git_ref.as_deref(), commit) {
+        (None, _, _) => "upload".into(),
+        (_, Some(git_ref), Some(commit)) => format!("{} ({})", git_ref, commit),
+        (_, None, Some(commit)) => commit.into(),
+        (_, git_ref, None) => git_ref.unwrap_or("-").into(),
+    }
+}
+
+/// how long ago a unix time in milliseconds was
+fn ago(time: u64) -> String {
+    let secs = (get_unix_time() as u64).saturating_sub(time) / 1000;
+    match secs {
+        0..=59 => format!("{}s ago", secs),
+        60..=3599 => format!("{}m ago", secs / 60),
+        3600..=86399 => format!("{}h ago", secs / 3600),
+        _ => format!("{}d ago", secs / 86400),
+    }
+}
+
+pub async fn secrets(opts: &Secrets) -> Result<(), Error> {
+    let app = get_app(&opts.app);
+    let (owner, name) = split_repo(&app);
+    let path = format!("/secrets/{}/{}", owner, name);
+
+    match &opts.action {
+        SecretsAction::List => {
+            let mut res = CLIENT.get(&path).await?;
+            check_response(&mut res).await?;
+
+            let secrets: BTreeMap<String, String> = res.body_json().await?;
+            if secrets.is_empty() {
+                println!("{} has no secrets", &app);
+            }
+            for (key, value) in secrets {
+                println!("{}={}", key, value);
+            }
+        }
+        SecretsAction::Set { key, value } => {
+            let mut res = CLIENT
+                .put(format!("{}/{}", &path, key))
+                .body(value.as_str())
+                .await?;
+            check_response(&mut res).await?;
+            println!("{}", res.body_string().await?);
+        }
+        SecretsAction::Unset { key } => {
+            let mut res = CLIENT.delete(format!("{}/{}", &path, key)).await?;
+            check_response(&mut res).await?;
+            println!("{}", res.body_string().await?);
+        }
+    }
 
     Ok(())
 }
@@ -0,0 +1,114 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// the body of every error response from the server
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ApiError {
+    pub code: String,
+    pub message: String,
+    pub details: Option<String>,
+}
+
+//* POST /deploy
+// answers with server-sent events: `progress` with a line of text,
+// then `done` with the `AppStatus` of the app, or `error` with an `ApiError`
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DeployParams {
+    pub repo: String,
+    /// a branch, tag or commit to deploy instead of the default branch.
+    /// pushes only redeploy the app when it's a branch
+    #[serde(rename = "ref")]
+    pub git_ref: Option<String>,
+    /// how many commits of history to clone, overriding the server's `depth`. 0 is the full history
+    pub depth: Option<u32>,
+    pub force: Option<bool>,
+}
+
+//* POST /upload/:owner/:name
+// the body is a gzipped tarball of the app's working tree.
+// answers with the same events as `/deploy`
+#[derive(Debug, Serialize, Deserialize)]
+pub struct UploadParams {
+    pub force: Option<bool>,
+}
+
+//* GET /apps/:owner/:name/logs
+// answers with a `log` server-sent event for every line
+#[derive(Debug, Serialize, Deserialize)]
+pub struct LogsParams {
+    /// how many of the last lines to send, defaults to 100
+    pub tail: Option<usize>,
+    /// keep sending the lines written to the log afterwards
+    pub follow: Option<bool>,
+    /// the process to read the log of, defaults to `web`
+    pub process: Option<String>,
+}
+
+/// the status of every process of an app, by process name
+pub type AppStatus = BTreeMap<String, ProcessStatus>;
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "status", rename_all = "lowercase")]
+pub enum ProcessStatus {
+    Running {
+        pid: u32,
+        cmd: String,
+        uptime: u64,
+        restarts: u32,
+    },
+    Exited {
+        pid: u32,
+        cmd: String,
+        code: Option<i32>,
+        uptime: u64,
+        restarts: u32,
+    },
+}
+
+/// an app in the server's registry
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct App {
+    /// `owner/name`
+    pub repo: String,
+    /// the git url the app is cloned from, `None` if it was uploaded
+    pub url: Option<String>,
+    /// the branch, tag or commit the app was deployed from
+    #[serde(rename = "ref")]
+    pub git_ref: Option<String>,
+    /// the depth the app was cloned with
+    pub depth: Option<u32>,
+    /// the deployed commit
+    pub commit: Option<String>,
+    /// the command of every process, from the app's pie.toml
+    #[serde(default)]
+    pub processes: BTreeMap<String, String>,
+    pub status: DeployStatus,
+    /// why the last deploy failed
+    pub error: Option<String>,
+    /// unix time in milliseconds of the last deploy
+    pub deployed_at: u64,
+    /// stopped with `POST /apps/:owner/:name/stop`, so pushes and restarts of the server
+    /// don't start it until it's started or deployed again
+    #[serde(default)]
+    pub stopped: bool,
+}
+
+/// how the last deploy of an app went
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DeployStatus {
+    Deploying,
+    Deployed,
+    Failed,
+}
+
+//* GET /apps
+//* GET /apps/:owner/:name
+/// an app of the registry, along with its processes
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AppInfo {
+    #[serde(flatten)]
+    pub app: App,
+    /// the processes the server is running, empty if the app isn't running
+    pub process_status: AppStatus,
+}
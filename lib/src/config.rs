@@ -1,8 +1,11 @@
-use crate::PROJECT_DIRS;
+use crate::{utils::generate_token, PROJECT_DIRS};
 use async_std::{fs::read_to_string, path::PathBuf};
 use serde::{Deserialize, Serialize};
 use serde_json;
-use std::default::Default;
+use std::{
+    collections::{BTreeMap, HashMap},
+    default::Default,
+};
 use toml;
 
 // === SERVER CONFIG ===
@@ -12,6 +15,16 @@ pub struct ServerConfig {
     pub url: String,
     pub port: Option<u16>,
     pub gh_token: String,
+    /// bearer tokens accepted by the management api
+    #[serde(default)]
+    pub tokens: Vec<String>,
+    /// git hosts other than github.com, by hostname
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub hosts: BTreeMap<String, HostConfig>,
+    /// clone only this many commits of history, unless a deploy asks for another depth.
+    /// full history if not set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub depth: Option<u32>,
 }
 
 impl Default for ServerConfig {
@@ -20,10 +33,33 @@ impl Default for ServerConfig {
             url: "https://example.com".into(),
             port: Some(6969),
             gh_token: "<your github token>".into(),
+            tokens: vec![generate_token()],
+            hosts: BTreeMap::new(),
+            depth: None,
         }
     }
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HostConfig {
+    #[serde(rename = "type")]
+    pub kind: HostKind,
+    /// used to clone private repos and to create webhooks
+    pub token: Option<String>,
+    /// defaults to the scheme and host of the repo url
+    pub api_url: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HostKind {
+    GitHub,
+    GitLab,
+    Gitea,
+    /// any other git server. repos can be cloned, but pushes aren't redeployed
+    Git,
+}
+
 pub fn get_server_config() -> ServerConfig {
     let config_dir = PROJECT_DIRS.config_dir();
     let config_path = config_dir.join("pie-server.toml");
@@ -32,23 +68,38 @@ pub fn get_server_config() -> ServerConfig {
 
     let config_file = std::fs::read_to_string(&config_path);
 
-    let config: ServerConfig = match config_file {
+    let mut config: ServerConfig = match config_file {
         Ok(file) => toml::from_str(&file).unwrap(),
-        Err(_) => create_default_server_config(config_path.to_str().unwrap())
-            .unwrap_or(ServerConfig::default()),
+        Err(_) => create_default_server_config(config_path.to_str().unwrap()).unwrap_or_default(),
     };
 
+    // configs from before api tokens existed get one, so the api is never left open
+    if config.tokens.is_empty() {
+        config.tokens.push(generate_token());
+        let config_str = toml::to_string_pretty(&config).unwrap();
+        if let Err(e) = std::fs::write(&config_path, config_str) {
+            warn!("Unable to save the new api token to the config file: {}", e);
+        }
+    }
+
     debug!("{:?}", &config);
 
-    return config;
+    config
 }
 
 fn create_default_server_config(path: &str) -> Result<ServerConfig, std::io::Error> {
     let default_config = ServerConfig::default();
     let config_str = toml::to_string_pretty(&default_config).unwrap();
 
+    std::fs::create_dir_all(PROJECT_DIRS.config_dir())?;
     match std::fs::write(path, &config_str) {
-        Ok(_) => Ok(default_config),
+        Ok(_) => {
+            info!(
+                "Created an api token in {}, use it to log in from the cli",
+                path
+            );
+            Ok(default_config)
+        }
         Err(err) => Err(err),
     }
 }
@@ -61,13 +112,66 @@ pub struct RepoConfigFile {
     pub install_command: Option<String>,
     pub build_command: Option<String>,
     pub start_command: Option<String>,
+    pub stop_signal: Option<String>,
+    pub stop_grace_period: Option<u64>,
+    pub restart: Option<String>,
+    pub restart_max_retries: Option<u32>,
+    pub restart_backoff: Option<u64>,
+    pub env: HashMap<String, String>,
+    pub platform: HashMap<String, PlatformConfig>,
+    pub prebuilt: Option<String>,
+    pub processes: BTreeMap<String, String>,
+    pub on_force_push: Option<String>,
+    pub lfs: Option<bool>,
+    pub autostart: Option<bool>,
 }
 #[derive(Debug, Deserialize)]
 pub struct RepoConfig {
     pub _type: Option<RepoConfigTypes>,
     pub install_command: Option<String>,
     pub build_command: Option<String>,
+    #[serde(default)]
     pub start_command: String,
+    /// signal sent to the app when stopping it, `SIGTERM` if not set
+    pub stop_signal: Option<String>,
+    /// seconds to wait for the app to exit before killing it
+    pub stop_grace_period: Option<u64>,
+    /// when to restart the app if it exits on its own, `on-failure` if not set
+    pub restart: Option<RestartPolicy>,
+    /// how many times in a row the app gets restarted before giving up
+    pub restart_max_retries: Option<u32>,
+    /// seconds to wait before the first restart, doubled on every retry
+    pub restart_backoff: Option<u64>,
+    /// the `[env]` table, passed to the install, build and start commands
+    #[serde(default)]
+    pub env: HashMap<String, String>,
+    /// `[platform.<name>]` tables, already applied by `get_repo_config`
+    #[serde(default)]
+    pub platform: HashMap<String, PlatformConfig>,
+    /// name of an asset in the latest github release, downloaded instead of running the build command.
+    /// it replaces the program of the start command. `{arch}` and `{os}` are replaced with the server's
+    pub prebuilt: Option<String>,
+    /// the `[processes]` table, like a Procfile. after `get_repo_config` it has every process to run,
+    /// with the start command as `web`
+    #[serde(default)]
+    pub processes: BTreeMap<String, String>,
+    /// what a redeploy does when the branch was force-pushed, `fail` if not set
+    pub on_force_push: Option<ForcePushPolicy>,
+    /// download the git lfs files of the repo on clone and pull, needs git-lfs on the server
+    pub lfs: Option<bool>,
+    /// start the app when the server starts, `true` if not set
+    pub autostart: Option<bool>,
+}
+
+/// overrides for a platform, where the name is an os (`linux`), an arch (`x86_64`, `aarch64`, `armv7`)
+/// or both (`armv7-linux`)
+#[derive(Debug, Default, Deserialize)]
+pub struct PlatformConfig {
+    pub install_command: Option<String>,
+    pub build_command: Option<String>,
+    pub start_command: Option<String>,
+    #[serde(default)]
+    pub env: HashMap<String, String>,
 }
 
 impl Default for RepoConfig {
@@ -77,14 +181,51 @@ impl Default for RepoConfig {
             install_command: None,
             build_command: None,
             start_command: "".into(),
+            stop_signal: None,
+            stop_grace_period: None,
+            restart: None,
+            restart_max_retries: None,
+            restart_backoff: None,
+            env: HashMap::new(),
+            platform: HashMap::new(),
+            prebuilt: None,
+            processes: BTreeMap::new(),
+            on_force_push: None,
+            lfs: None,
+            autostart: None,
         }
     }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
 pub enum RepoConfigTypes {
     NodeJS,
     NodeTS,
+    Rust,
+    Python,
+}
+
+/// the process the start command runs as
+pub const WEB_PROCESS: &str = "web";
+
+/// the virtualenv python projects get, relative to the repo
+pub const PYTHON_VENV_DIR: &str = ".venv";
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum RestartPolicy {
+    Never,
+    OnFailure,
+    Always,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ForcePushPolicy {
+    /// leave the app as it is and report the error
+    Fail,
+    /// hard-reset to the remote branch, and remove untracked files like build artifacts
+    Reset,
 }
 
 #[derive(Debug)]
@@ -110,27 +251,119 @@ pub async fn get_repo_config(path: PathBuf) -> Result<RepoConfig, RepoConfigErro
         error!("invalid config file: {:?}", &p);
         return Err(RepoConfigError::InvalidTOML);
     };
-    let config_file = config_file_res.ok().unwrap().unwrap_or_default();
+    let mut config_file = config_file_res.ok().unwrap().unwrap_or_default();
+    apply_platform_overrides(&mut config_file, &get_platform_names());
+
+    let procfile = parse_procfile(path.clone()).await.unwrap_or_default();
+
+    let default_config = match get_default_repo_config(path.clone()).await {
+        Ok(c) => c,
+        // a pie.toml with a start command or a Procfile doesn't need auto-detection
+        Err(_)
+            if !config_file.start_command.is_empty()
+                || !config_file.processes.is_empty()
+                || !procfile.is_empty() =>
+        {
+            RepoConfig::default()
+        }
+        Err(e) => return Err(e),
+    };
 
-    let default_config_res = get_default_repo_config(path.clone()).await;
-    if default_config_res.is_err() {
-        return default_config_res;
+    // from weakest to strongest: detected start command, Procfile, pie.toml start command, [processes]
+    let mut processes = BTreeMap::new();
+    if !default_config.start_command.is_empty() {
+        processes.insert(WEB_PROCESS.to_string(), default_config.start_command);
+    }
+    processes.extend(procfile);
+    if !config_file.start_command.is_empty() {
+        processes.insert(WEB_PROCESS.to_string(), config_file.start_command);
     }
-    let default_config = default_config_res.ok().unwrap();
+    processes.extend(config_file.processes);
+    processes.retain(|name, cmd| {
+        let valid = is_valid_process_name(name) && !cmd.trim().is_empty();
+        if !valid {
+            warn!("ignoring invalid process {:?}: {:?}", name, cmd);
+        }
+        valid
+    });
+
+    let start_command = processes
+        .get(WEB_PROCESS)
+        .or_else(|| processes.values().next())
+        .cloned()
+        .unwrap_or_default();
 
-    Ok(RepoConfig {
+    let config = RepoConfig {
         _type: value_or_def(config_file._type, default_config._type),
         install_command: value_or_def_null(
             config_file.install_command,
             default_config.install_command,
         ),
         build_command: value_or_def_null(config_file.build_command, default_config.build_command),
-        start_command: if config_file.start_command != "".to_string() {
-            config_file.start_command
-        } else {
-            default_config.start_command
-        },
-    })
+        start_command,
+        stop_signal: value_or_def(config_file.stop_signal, default_config.stop_signal),
+        stop_grace_period: value_or_def(
+            config_file.stop_grace_period,
+            default_config.stop_grace_period,
+        ),
+        restart: value_or_def(config_file.restart, default_config.restart),
+        restart_max_retries: value_or_def(
+            config_file.restart_max_retries,
+            default_config.restart_max_retries,
+        ),
+        restart_backoff: value_or_def(config_file.restart_backoff, default_config.restart_backoff),
+        env: default_config
+            .env
+            .into_iter()
+            .chain(config_file.env)
+            .collect(),
+        platform: HashMap::new(),
+        prebuilt: value_or_def(config_file.prebuilt, default_config.prebuilt),
+        processes,
+        on_force_push: config_file.on_force_push,
+        lfs: config_file.lfs,
+        autostart: config_file.autostart,
+    };
+
+    if config.processes.is_empty() {
+        return Err(RepoConfigError::MissingCommands);
+    }
+    Ok(config)
+}
+
+/// the names `[platform.<name>]` tables can use to match this machine, least specific first
+fn get_platform_names() -> Vec<String> {
+    let os = std::env::consts::OS;
+    let mut arches = vec![std::env::consts::ARCH];
+    // the arch is just `arm` for every 32-bit arm, but a pi is more likely to be called armv7
+    if cfg!(all(target_arch = "arm", target_feature = "v7")) {
+        arches.push("armv7");
+    }
+
+    let mut names = vec![os.to_string()];
+    names.extend(arches.iter().map(|a| a.to_string()));
+    names.extend(arches.iter().map(|a| format!("{}-{}", a, os)));
+    names
+}
+
+fn apply_platform_overrides(config: &mut RepoConfig, platforms: &[String]) {
+    let mut overrides = std::mem::take(&mut config.platform);
+
+    for name in platforms {
+        let platform = match overrides.remove(name) {
+            Some(p) => p,
+            None => continue,
+        };
+        debug!("applying platform overrides for {:?}", name);
+
+        config.install_command =
+            value_or_def(platform.install_command, config.install_command.take());
+        config.build_command = value_or_def(platform.build_command, config.build_command.take());
+        if let Some(start_command) = platform.start_command {
+            config.start_command = start_command;
+        }
+        config.env.extend(platform.env);
+    }
 }
 
 async fn get_default_repo_config(path: PathBuf) -> Result<RepoConfig, RepoConfigError> {
@@ -143,8 +376,7 @@ async fn get_default_repo_config(path: PathBuf) -> Result<RepoConfig, RepoConfig
         let yarn = use_yarn(path.clone()).await;
         let install_command = npm_yarn_run("install", yarn);
 
-        if j.is_some() {
-            let j = j.unwrap();
+        if let Some(j) = j {
             let build_command = if j.scripts.pie_build.is_some() {
                 Some(npm_yarn_run("run pie-build", yarn))
             } else {
@@ -163,8 +395,9 @@ async fn get_default_repo_config(path: PathBuf) -> Result<RepoConfig, RepoConfig
             return Ok(RepoConfig {
                 _type: Some(RepoConfigTypes::NodeJS),
                 install_command: Some(install_command),
-                build_command: build_command,
-                start_command: start_command,
+                build_command,
+                start_command,
+                ..Default::default()
             });
         } else {
             // no sensible defaults found, returning to the primitive way
@@ -173,19 +406,154 @@ async fn get_default_repo_config(path: PathBuf) -> Result<RepoConfig, RepoConfig
                 install_command: Some("npm install".into()),
                 build_command: None,
                 start_command: "npm run start".into(),
+                ..Default::default()
             });
         }
     };
 
+    //== RUST PROJECT ==//
+    let mut cargo_toml = path.clone();
+    cargo_toml.push("Cargo.toml");
+
+    if cargo_toml.is_file().await {
+        let start_command = match find_cargo_bin(path.clone()).await {
+            Some(bin) => format!("./target/release/{}", bin),
+            // nothing to run, unless pie.toml has a start command
+            None => "".into(),
+        };
+
+        return Ok(RepoConfig {
+            _type: Some(RepoConfigTypes::Rust),
+            install_command: None,
+            build_command: Some("cargo build --release".into()),
+            start_command,
+            ..Default::default()
+        });
+    }
+
+    //== PYTHON PROJECT ==//
+    let requirements_txt = path.join("requirements.txt");
+    let pyproject_toml = path.join("pyproject.toml");
+    let pipfile = path.join("Pipfile");
+
+    if requirements_txt.is_file().await || pyproject_toml.is_file().await || pipfile.is_file().await
+    {
+        // every command runs inside the repo's virtualenv, which the runner creates before installing
+        let venv = path.join(PYTHON_VENV_DIR);
+        let mut env = HashMap::new();
+        env.insert(
+            "PATH".to_string(),
+            format!(
+                "{}:{}",
+                venv.join("bin").to_string_lossy(),
+                std::env::var("PATH").unwrap_or_default()
+            ),
+        );
+        env.insert(
+            "VIRTUAL_ENV".to_string(),
+            venv.to_string_lossy().to_string(),
+        );
+
+        let pyproject = parse_pyproject_toml(pyproject_toml.clone()).await;
+        let tool_pie = pyproject
+            .as_ref()
+            .and_then(|p| p.tool.as_ref())
+            .and_then(|t| t.pie.clone())
+            .unwrap_or_default();
+
+        let install_command = if pipfile.is_file().await {
+            env.insert("PIPENV_VENV_IN_PROJECT".to_string(), "1".to_string());
+            "pipenv install --deploy"
+        } else if requirements_txt.is_file().await {
+            "pip install -r requirements.txt"
+        } else if path.join("poetry.lock").is_file().await {
+            "poetry install --no-root"
+        } else {
+            "pip install ."
+        };
+
+        let start_command = match tool_pie.start_command {
+            Some(cmd) => cmd,
+            // a Procfile gets picked up by get_repo_config
+            None => {
+                if path.join("main.py").is_file().await {
+                    "python main.py".into()
+                } else if path.join("app.py").is_file().await {
+                    "python app.py".into()
+                } else {
+                    // nothing to run, unless pie.toml or a Procfile has a start command
+                    "".into()
+                }
+            }
+        };
+
+        return Ok(RepoConfig {
+            _type: Some(RepoConfigTypes::Python),
+            install_command: Some(
+                tool_pie
+                    .install_command
+                    .unwrap_or_else(|| install_command.into()),
+            ),
+            build_command: tool_pie.build_command,
+            start_command,
+            env,
+            ..Default::default()
+        });
+    }
+
     Err(RepoConfigError::MissingCommands)
 }
 
+#[derive(Debug, Deserialize)]
+struct PyProjectToml {
+    tool: Option<PyProjectTool>,
+}
+#[derive(Debug, Deserialize)]
+struct PyProjectTool {
+    pie: Option<PyProjectPie>,
+}
+/// the `[tool.pie]` table of pyproject.toml, for python projects that don't want a pie.toml
+#[derive(Debug, Default, Clone, Deserialize)]
+struct PyProjectPie {
+    install_command: Option<String>,
+    build_command: Option<String>,
+    start_command: Option<String>,
+}
+async fn parse_pyproject_toml(path: PathBuf) -> Option<PyProjectToml> {
+    let file = read_to_string(path).await.ok()?;
+    toml::from_str::<PyProjectToml>(&file).ok()
+}
+
+/// process names end up in file names, so they're kept to what heroku allows
+pub fn is_valid_process_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+}
+
+fn parse_procfile_str(file: &str) -> BTreeMap<String, String> {
+    file.lines()
+        .map(|l| l.trim())
+        .filter(|l| !l.starts_with('#'))
+        .filter_map(|l| l.split_once(':'))
+        .map(|(name, cmd)| (name.trim().to_string(), cmd.trim().to_string()))
+        .filter(|(name, cmd)| !name.is_empty() && !cmd.is_empty())
+        .collect()
+}
+
+async fn parse_procfile(path: PathBuf) -> Option<BTreeMap<String, String>> {
+    let file = read_to_string(path.join("Procfile")).await.ok()?;
+    Some(parse_procfile_str(&file))
+}
+
 #[derive(Debug, Deserialize)]
 struct PackageJSON {
     scripts: PackageJSONScripts,
 }
 #[derive(Debug, Deserialize)]
 struct PackageJSONScripts {
+    #[allow(dead_code)]
     build: Option<String>,
     start: Option<String>,
     pie_build: Option<String>,
@@ -196,12 +564,89 @@ async fn parse_pkg_json(path: PathBuf) -> Option<PackageJSON> {
     if file.is_err() {
         return None;
     }
-    let j = serde_json::from_str::<PackageJSON>(&file.unwrap());
+    serde_json::from_str::<PackageJSON>(&file.unwrap()).ok()
+}
+
+#[derive(Debug, Deserialize)]
+struct CargoToml {
+    package: Option<CargoPackage>,
+    #[serde(default)]
+    bin: Vec<CargoBin>,
+    workspace: Option<CargoWorkspace>,
+}
+#[derive(Debug, Deserialize)]
+struct CargoPackage {
+    name: String,
+}
+#[derive(Debug, Deserialize)]
+struct CargoBin {
+    name: String,
+}
+#[derive(Debug, Deserialize)]
+struct CargoWorkspace {
+    #[serde(default)]
+    members: Vec<String>,
+}
+async fn parse_cargo_toml(path: PathBuf) -> Option<CargoToml> {
+    let file = read_to_string(path).await.ok()?;
+    toml::from_str::<CargoToml>(&file).ok()
+}
+
+/// the binary target `cargo build --release` produces for a crate, or for the first workspace member with one
+async fn find_cargo_bin(path: PathBuf) -> Option<String> {
+    let cargo = parse_cargo_toml(path.join("Cargo.toml")).await?;
+
+    if let Some(bin) = get_package_bin(&path, &cargo).await {
+        return Some(bin);
+    }
 
-    match j {
-        Ok(j) => Some(j),
-        Err(_) => None,
+    for member in cargo.workspace.map(|w| w.members).unwrap_or_default() {
+        for member_path in expand_workspace_member(&path, &member) {
+            let member_cargo = match parse_cargo_toml(member_path.join("Cargo.toml")).await {
+                Some(c) => c,
+                None => continue,
+            };
+            if let Some(bin) = get_package_bin(&member_path, &member_cargo).await {
+                return Some(bin);
+            }
+        }
     }
+
+    None
+}
+
+async fn get_package_bin(path: &PathBuf, cargo: &CargoToml) -> Option<String> {
+    let package = cargo.package.as_ref()?;
+
+    // with multiple `[[bin]]`s, the one named after the package is most likely the main one
+    if let Some(bin) = cargo.bin.iter().find(|b| b.name == package.name) {
+        return Some(bin.name.clone());
+    }
+    if let Some(bin) = cargo.bin.first() {
+        return Some(bin.name.clone());
+    }
+    if path.join("src/main.rs").is_file().await {
+        return Some(package.name.clone());
+    }
+    None
+}
+
+/// workspace members can be globs, but only `dir/*` is common enough to bother with
+fn expand_workspace_member(path: &PathBuf, member: &str) -> Vec<PathBuf> {
+    let dir = match member.strip_suffix("/*") {
+        Some(dir) => path.join(dir),
+        None => return vec![path.join(member)],
+    };
+
+    let mut members = match std::fs::read_dir(&dir) {
+        Ok(entries) => entries
+            .filter_map(|e| e.ok())
+            .map(|e| PathBuf::from(e.path()))
+            .collect::<Vec<PathBuf>>(),
+        Err(_) => vec![],
+    };
+    members.sort();
+    members
 }
 
 async fn use_yarn(path: PathBuf) -> bool {
@@ -219,10 +664,57 @@ fn npm_yarn_run(cmd: &str, yarn: bool) -> String {
 
 // === CLI Config ===
 
-// TODO: parse cli config file
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct CliConfig {
-    pub server_url: Option<String>,
+    /// the server used when `--server` isn't given
+    pub default: Option<String>,
+    #[serde(default)]
+    pub servers: BTreeMap<String, ServerProfile>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ServerProfile {
+    pub url: String,
+    pub token: Option<String>,
+}
+
+impl CliConfig {
+    /// the server with the given name, or the default one.
+    /// with no default, a lone server is used
+    pub fn server(&self, name: Option<&str>) -> Option<(&String, &ServerProfile)> {
+        match name.or(self.default.as_deref()) {
+            Some(name) => self.servers.get_key_value(name),
+            None if self.servers.len() == 1 => self.servers.iter().next(),
+            None => None,
+        }
+    }
+}
+
+pub fn get_cli_config_path() -> std::path::PathBuf {
+    PROJECT_DIRS.config_dir().join("pie-cli.toml")
+}
+
+/// a missing config file is just the default config
+pub fn get_cli_config() -> Result<CliConfig, toml::de::Error> {
+    match std::fs::read_to_string(get_cli_config_path()) {
+        Ok(file) => toml::from_str(&file),
+        Err(_) => Ok(CliConfig::default()),
+    }
+}
+
+/// only readable by the user, since it has the api tokens in it
+pub fn save_cli_config(config: &CliConfig) -> Result<(), std::io::Error> {
+    let path = get_cli_config_path();
+    let config_str = toml::to_string_pretty(config).unwrap();
+
+    std::fs::create_dir_all(PROJECT_DIRS.config_dir())?;
+    std::fs::write(&path, config_str)?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o600))?;
+    }
+    Ok(())
 }
 
 fn value_or_def<T>(value: Option<T>, def: Option<T>) -> Option<T> {
@@ -241,3 +733,128 @@ fn value_or_def_null(value: Option<String>, def: Option<String>) -> Option<Strin
     }
     def
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn platform_overrides() {
+        let mut config = toml::from_str::<RepoConfig>(
+            r#"
+            start_command = "node ."
+            on_force_push = "reset"
+
+            [env]
+            PORT = "8080"
+            NODE_ENV = "production"
+
+            [platform.linux.env]
+            PORT = "80"
+
+            [platform.armv7]
+            install_command = "NONE"
+            start_command = "node --max-old-space-size=256 ."
+
+            [platform.armv7-linux.env]
+            PORT = "3000"
+
+            [platform.x86_64]
+            build_command = "npm run build"
+            "#,
+        )
+        .unwrap();
+
+        apply_platform_overrides(
+            &mut config,
+            &[
+                "linux".into(),
+                "arm".into(),
+                "armv7".into(),
+                "armv7-linux".into(),
+            ],
+        );
+
+        assert_eq!(config.install_command, Some("NONE".into()));
+        assert_eq!(config.build_command, None);
+        assert_eq!(config.start_command, "node --max-old-space-size=256 .");
+        assert_eq!(config.env["PORT"], "3000");
+        assert_eq!(config.env["NODE_ENV"], "production");
+        assert!(config.platform.is_empty());
+        assert_eq!(config.on_force_push, Some(ForcePushPolicy::Reset));
+    }
+
+    #[test]
+    fn cargo_workspace_bin() {
+        // a virtual workspace, with a lib-only member sorted before the one with a binary
+        let workspace = std::env::temp_dir().join(format!("pie-cargo-test-{}", std::process::id()));
+        let write = |path: &str, contents: &str| {
+            let path = workspace.join(path);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, contents).unwrap();
+        };
+        write("Cargo.toml", "[workspace]\nmembers = [\"crates/*\"]\n");
+        write(
+            "crates/core/Cargo.toml",
+            "[package]\nname = \"core\"\nversion = \"0.1.0\"\n",
+        );
+        write("crates/core/src/lib.rs", "");
+        write(
+            "crates/server/Cargo.toml",
+            "[package]\nname = \"server\"\nversion = \"0.1.0\"\n\n[[bin]]\nname = \"serverd\"\npath = \"src/serverd.rs\"\n",
+        );
+        write("crates/server/src/serverd.rs", "fn main() {}");
+
+        let bin = async_std::task::block_on(find_cargo_bin(workspace.clone().into()));
+        let lib = async_std::task::block_on(find_cargo_bin(workspace.join("crates/core").into()));
+        std::fs::remove_dir_all(&workspace).unwrap();
+
+        assert_eq!(bin, Some("serverd".into()));
+        assert_eq!(lib, None);
+    }
+
+    #[test]
+    fn procfile() {
+        let processes = parse_procfile_str(
+            "# comment\nweb: gunicorn app:app --bind 0.0.0.0:8000\n\nworker:python worker.py\n  clock: python clock.py\n",
+        );
+
+        assert_eq!(processes.len(), 3);
+        assert_eq!(processes["web"], "gunicorn app:app --bind 0.0.0.0:8000");
+        assert_eq!(processes["worker"], "python worker.py");
+        assert_eq!(processes["clock"], "python clock.py");
+
+        assert!(is_valid_process_name("worker_2"));
+        assert!(!is_valid_process_name("../../etc"));
+    }
+
+    #[test]
+    fn cli_server_profiles() {
+        let mut config = toml::from_str::<CliConfig>(
+            r#"
+            [servers.home]
+            url = "http://127.0.0.1:6969"
+            "#,
+        )
+        .unwrap();
+        // a lone server is used even without a default
+        assert_eq!(config.server(None).unwrap().0, "home");
+        assert!(config.server(Some("work")).is_none());
+
+        config.servers.insert(
+            "work".into(),
+            ServerProfile {
+                url: "https://pie.example.com".into(),
+                token: Some("pie_abc".into()),
+            },
+        );
+        assert!(config.server(None).is_none());
+
+        config.default = Some("work".into());
+        assert_eq!(
+            config.server(None).unwrap().1.url,
+            "https://pie.example.com"
+        );
+        assert_eq!(config.server(Some("home")).unwrap().0, "home");
+    }
+}
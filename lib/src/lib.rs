@@ -4,6 +4,7 @@ extern crate lazy_static;
 extern crate log;
 use directories::ProjectDirs;
 
+pub mod api;
 pub mod config;
 pub mod utils;
 
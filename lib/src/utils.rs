@@ -1,6 +1,6 @@
 use crate::PROJECT_DIRS;
 use async_std::path::PathBuf;
-use rand;
+use rand::{rngs::OsRng, RngCore};
 use std::{
     io::Error,
     process::{Command, Stdio},
@@ -20,16 +20,64 @@ pub fn repo_to_url(repo: &str) -> String {
     format!("https://github.com/{}", repo)
 }
 
+/// a url to clone a repo from without ssh keys, for any url or git remote `url_to_repo` understands
+pub fn normalize_git_url(url: &str) -> Option<String> {
+    url_to_repo(url)?;
+
+    let url = url.trim();
+    let (host, path) = match Url::from_str(url) {
+        Ok(u) if u.scheme() == "ssh" => (u.host_str()?.to_string(), u.path().to_string()),
+        Ok(u) if u.host_str().is_some() => return Some(url.trim_end_matches('/').to_string()),
+        Ok(_) => return None,
+        Err(_) => {
+            let (host, path) = url.split_once(':')?;
+            let host = host.rsplit('@').next()?;
+            (host.to_string(), path.to_string())
+        }
+    };
+
+    Some(format!("https://{}/{}", host, path.trim_matches('/')))
+}
+
+/// the `owner/name` of a repo from a url or git remote. understands https and ssh remotes,
+/// like `https://github.com/owner/name.git` and `git@github.com:owner/name.git`.
+/// repos nested deeper, like in gitlab subgroups, can't be told apart by `owner/name` so they're rejected
 pub fn url_to_repo(url: &str) -> Option<String> {
-    let u = match Url::from_str(&url) {
-        Ok(x) => x,
-        Err(_) => return None,
+    let url = url.trim();
+    let path = match Url::from_str(url) {
+        Ok(u) => u.path().to_string(),
+        // scp-like ssh remotes aren't urls
+        Err(_) => url
+            .split_once(':')
+            .filter(|(host, _)| !host.contains('/'))?
+            .1
+            .to_string(),
+    };
+
+    let segments = path
+        .split('/')
+        .filter(|s| !s.is_empty())
+        .collect::<Vec<_>>();
+    let (owner, name) = match segments[..] {
+        [owner, name] => (owner, name),
+        _ => return None,
     };
-    debug!("{:?}", &u);
+    let name = name.strip_suffix(".git").unwrap_or(name);
+    if !is_valid_repo_part(owner) || !is_valid_repo_part(name) {
+        return None;
+    }
 
-    let path = u.path_segments()?.collect::<Vec<&str>>();
+    Some(format!("{}/{}", owner, name))
+}
 
-    Some(path[0].to_owned() + "/" + path[1])
+/// whether an owner or a name of a repo is safe to use in paths on disk.
+/// only letters, digits, `-`, `_` and `.` are allowed, and they can't start with a `.`
+pub fn is_valid_repo_part(part: &str) -> bool {
+    !part.is_empty()
+        && !part.starts_with('.')
+        && part
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.')
 }
 
 pub fn split_repo(repo: &str) -> (&str, &str) {
@@ -37,11 +85,17 @@ pub fn split_repo(repo: &str) -> (&str, &str) {
     (v[0], v[1])
 }
 
+/// where the apps are cloned to, as `<owner>/<name>`
+pub fn get_repos_dir() -> PathBuf {
+    let mut d = PathBuf::from(PROJECT_DIRS.data_local_dir());
+    d.push("repos");
+    d
+}
+
 pub fn repo_to_path(repo: &str) -> PathBuf {
     let r = split_repo(repo);
 
-    let mut d = PathBuf::from(PROJECT_DIRS.data_local_dir());
-    d.push("repos");
+    let mut d = get_repos_dir();
     d.push(r.0);
     d.push(r.1);
 
@@ -65,12 +119,91 @@ pub fn create_logs_dir() -> Result<(), Error> {
     Ok(())
 }
 pub fn repo_to_log_file(repo: &str) -> PathBuf {
-    let name = repo_to_pie_name(&repo);
+    let name = repo_to_pie_name(repo);
 
     let mut d = get_logs_dir();
     d.push(format!("{}.log", name));
     d
 }
+/// the `web` process logs to the repo's log file, the others get their own next to it
+pub fn repo_process_to_log_file(repo: &str, process: &str) -> PathBuf {
+    if process == crate::config::WEB_PROCESS {
+        return repo_to_log_file(repo);
+    }
+    let name = repo_to_pie_name(repo);
+
+    let mut d = get_logs_dir();
+    d.push(format!("{}.{}.log", name, process));
+    d
+}
+
+/// the registry of every deployed app
+pub fn get_registry_file() -> PathBuf {
+    let mut d = PathBuf::from(PROJECT_DIRS.data_local_dir());
+    d.push("apps.json");
+    d
+}
+
+fn get_webhook_secrets_dir() -> PathBuf {
+    let mut d = PathBuf::from(PROJECT_DIRS.data_local_dir());
+    d.push("webhook-secrets");
+    d
+}
+pub fn create_webhook_secrets_dir() -> Result<(), Error> {
+    let d = get_webhook_secrets_dir();
+    std::fs::create_dir_all(&d)?;
+    Ok(())
+}
+pub fn repo_to_webhook_secret_file(repo: &str) -> PathBuf {
+    let name = repo_to_pie_name(repo);
+
+    let mut d = get_webhook_secrets_dir();
+    d.push(name);
+    d
+}
+
+fn get_deploy_keys_dir() -> PathBuf {
+    let mut d = PathBuf::from(PROJECT_DIRS.data_local_dir());
+    d.push("deploy-keys");
+    d
+}
+pub fn create_deploy_keys_dir() -> Result<(), Error> {
+    let d = get_deploy_keys_dir();
+    std::fs::create_dir_all(&d)?;
+    Ok(())
+}
+/// the private key, the public one is next to it with a `.pub` extension
+pub fn repo_to_deploy_key_file(repo: &str) -> PathBuf {
+    let name = repo_to_pie_name(repo);
+
+    let mut d = get_deploy_keys_dir();
+    d.push(name);
+    d
+}
+/// the host keys the ssh of git-lfs checks, next to the deploy keys it authenticates with
+pub fn get_known_hosts_file() -> PathBuf {
+    let mut d = get_deploy_keys_dir();
+    d.push("known_hosts");
+    d
+}
+
+fn get_secrets_dir() -> PathBuf {
+    let mut d = PathBuf::from(PROJECT_DIRS.data_local_dir());
+    d.push("secrets");
+    d
+}
+pub fn create_secrets_dir() -> Result<(), Error> {
+    let d = get_secrets_dir();
+    std::fs::create_dir_all(&d)?;
+    Ok(())
+}
+pub fn repo_to_secrets_file(repo: &str) -> PathBuf {
+    let name = repo_to_pie_name(repo);
+
+    let mut d = get_secrets_dir();
+    d.push(format!("{}.toml", name));
+    d
+}
 
 pub fn string_to_cmd_and_args(s: &str) -> (&str, Vec<&str>) {
     let v = s.split_ascii_whitespace().collect::<Vec<&str>>();
@@ -90,24 +223,63 @@ pub fn exec_sync(cmd: &str, dir: std::path::PathBuf) -> Result<String, Error> {
 
     let out = cmd.wait_with_output()?;
 
-    Ok(String::from_utf8(out.stdout).unwrap_or(String::new()))
+    Ok(String::from_utf8(out.stdout).unwrap_or_default())
 }
 
+/// a random key from the os rng, 32 bytes hex encoded
 pub fn generate_key() -> String {
-    let alphabet =
-        String::from("abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNPQRSTUVWXYZ0123456789/.;!@$");
+    let mut bytes = [0u8; 32];
+    OsRng.fill_bytes(&mut bytes);
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
 
-    let mut code = String::new();
+/// a new api token for the server's management api
+pub fn generate_token() -> String {
+    format!("pie_{}", generate_key())
+}
 
-    for _ in 0..16 {
-        let number = rand::random::<f32>() * (alphabet.len() as f32);
-        let number = number.round() as usize;
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-        match alphabet.chars().nth(number) {
-            Some(c) => code.push(c),
-            None => {}
-        }
+    #[test]
+    fn remotes_to_repo() {
+        let repo = Some("beni69/pie".to_string());
+        assert_eq!(url_to_repo("https://github.com/beni69/pie"), repo);
+        assert_eq!(url_to_repo("https://github.com/beni69/pie.git"), repo);
+        assert_eq!(url_to_repo("https://github.com/beni69/pie/"), repo);
+        assert_eq!(url_to_repo("git@github.com:beni69/pie.git"), repo);
+        assert_eq!(url_to_repo("ssh://git@github.com/beni69/pie.git\n"), repo);
+        assert_eq!(url_to_repo("https://github.com/beni69"), None);
+        assert_eq!(
+            url_to_repo("https://gitlab.com/group/sub/project.git"),
+            None
+        );
+        assert_eq!(url_to_repo("nope"), None);
+        assert_eq!(url_to_repo("git@x:../.."), None);
+        assert_eq!(url_to_repo("git@x:owner/.git"), None);
+        assert_eq!(url_to_repo("https://x/a b/c"), None);
+    }
+
+    #[test]
+    fn remotes_to_https() {
+        let url = |u| normalize_git_url(u).unwrap();
+        assert_eq!(
+            url("git@github.com:beni69/pie.git"),
+            "https://github.com/beni69/pie.git"
+        );
+        assert_eq!(
+            url("ssh://git@git.lan:2222/team/app.git"),
+            "https://git.lan/team/app.git"
+        );
+        assert_eq!(
+            url("http://git.lan:3000/team/app/"),
+            "http://git.lan:3000/team/app"
+        );
+        assert_eq!(
+            url("git://git.lan/team/app.git"),
+            "git://git.lan/team/app.git"
+        );
+        assert_eq!(normalize_git_url("https://git.lan/team"), None);
     }
-    debug!("{:?}", &code);
-    code
 }
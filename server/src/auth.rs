@@ -0,0 +1,61 @@
+use crate::{error::ServerError, CONFIG};
+use tide::{Middleware, Next, Request, Result};
+
+/// middleware requiring one of the api tokens from the server config as a bearer token
+pub struct TokenAuth;
+
+#[tide::utils::async_trait]
+impl Middleware<()> for TokenAuth {
+    async fn handle(&self, req: Request<()>, next: Next<'_, ()>) -> Result {
+        let token = match req
+            .header("Authorization")
+            .and_then(|h| bearer_token(h.last().as_str()))
+        {
+            Some(t) => t,
+            None => {
+                return Err(ServerError::Unauthorized(
+                    "Missing api token. Log in with the token from the server config first.".into(),
+                )
+                .into())
+            }
+        };
+
+        if !CONFIG
+            .tokens
+            .iter()
+            .any(|t| constant_time_eq(t.as_bytes(), token.as_bytes()))
+        {
+            warn!("request to {} with an invalid api token", req.url().path());
+            return Err(ServerError::Unauthorized("Invalid api token.".into()).into());
+        }
+
+        Ok(next.run(req).await)
+    }
+}
+
+fn bearer_token(header: &str) -> Option<&str> {
+    let (scheme, token) = header.trim().split_once(' ')?;
+    if !scheme.eq_ignore_ascii_case("bearer") {
+        return None;
+    }
+    Some(token.trim()).filter(|t| !t.is_empty())
+}
+
+// so response times don't leak how much of a token was right
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bearer_header() {
+        assert_eq!(bearer_token("Bearer pie_abc"), Some("pie_abc"));
+        assert_eq!(bearer_token("bearer  pie_abc "), Some("pie_abc"));
+        assert_eq!(bearer_token("Basic dXNlcjpwYXNz"), None);
+        assert_eq!(bearer_token("Bearer "), None);
+        assert_eq!(bearer_token("pie_abc"), None);
+    }
+}
@@ -0,0 +1,76 @@
+use crate::{github, secrets::write_private_file};
+use async_std::{fs, path::PathBuf};
+use pie_lib::utils::{get_known_hosts_file, repo_to_deploy_key_file};
+use ssh_key::{rand_core::OsRng, Algorithm, LineEnding, PrivateKey};
+use std::io::Error;
+
+// every repo gets its own ssh key, registered on the host as a read-only deploy key,
+// so the server never needs more access to a repo than cloning it
+
+/// the public key of a repo, generating the keypair if it doesn't have one yet.
+/// an existing key is kept, so registering it again finds it already there
+pub async fn get_or_create(repo: &str) -> Result<String, Error> {
+    let path = repo_to_deploy_key_file(repo);
+    let pub_path = public_key_file(repo);
+    if let Ok(public) = fs::read_to_string(&pub_path).await {
+        if path.is_file().await {
+            return Ok(public);
+        }
+    }
+
+    let (private, public) = generate(repo)?;
+    // the public key is written last, since it's what tells whether the pair exists
+    write_private_file(&path, private).await?;
+    write_private_file(&pub_path, &public).await?;
+
+    info!("generated a deploy key for {}", repo);
+    Ok(public)
+}
+
+/// a new ed25519 keypair in the openssh format, as the private and the public key
+fn generate(repo: &str) -> Result<(String, String), Error> {
+    let mut key = PrivateKey::random(&mut OsRng, Algorithm::Ed25519).map_err(Error::other)?;
+    key.set_comment(format!("pie {}", repo));
+    let private = key.to_openssh(LineEnding::LF).map_err(Error::other)?;
+    let public = key.public_key().to_openssh().map_err(Error::other)?;
+    Ok((private.to_string(), public))
+}
+
+/// the public and private key of a repo, for the credentials callback of git2
+pub fn get(repo: &str) -> Option<(String, String)> {
+    let path = repo_to_deploy_key_file(repo);
+    let public = std::fs::read_to_string(public_key_file(repo)).ok()?;
+    let private = std::fs::read_to_string(&path).ok()?;
+    Some((public, private))
+}
+
+/// github.com's host keys for the ssh of git-lfs, which doesn't go through the checks of git2.
+/// written atomically, since lfs pulls of other apps might be reading it
+pub async fn known_hosts_file() -> Result<PathBuf, Error> {
+    let path = get_known_hosts_file();
+    write_private_file(&path, github::KNOWN_HOSTS).await?;
+    Ok(path)
+}
+
+/// the private key's file with `.pub` added, like ssh-keygen names them
+pub fn public_key_file(repo: &str) -> PathBuf {
+    let mut path = repo_to_deploy_key_file(repo).into_os_string();
+    path.push(".pub");
+    path.into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn openssh_keypair() {
+        let (private, public) = generate("beni69/pie").unwrap();
+        assert!(public.starts_with("ssh-ed25519 "));
+        assert!(public.ends_with(" pie beni69/pie"));
+
+        let key = PrivateKey::from_openssh(&private).unwrap();
+        assert_eq!(key.public_key().to_openssh().unwrap(), public);
+        assert!(!key.is_encrypted());
+    }
+}
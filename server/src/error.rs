@@ -0,0 +1,264 @@
+use crate::{git::GitError, github::GitHubError, provider::HostError, runner::RunnerError};
+use pie_lib::{api::ApiError, config::RepoConfigError};
+use std::fmt;
+use tide::{Body, Response, StatusCode};
+
+/// every error a request can end in. handlers return it with `?`,
+/// and `json_errors` turns it into an `ApiError` response
+#[derive(Debug)]
+pub enum ServerError {
+    BadRequest(String),
+    Forbidden(String),
+    NotFound(String),
+    Unauthorized(String),
+    Git(GitError),
+    GitHub(GitHubError),
+    Host(HostError),
+    Runner(RunnerError),
+    RepoConfig(RepoConfigError),
+    Io(std::io::Error),
+}
+
+impl ServerError {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            Self::BadRequest(_) => StatusCode::BadRequest,
+            Self::Forbidden(_) => StatusCode::Forbidden,
+            Self::NotFound(_) => StatusCode::NotFound,
+            Self::Unauthorized(_) => StatusCode::Unauthorized,
+            Self::Git(e) => match e {
+                GitError::Exists => StatusCode::BadRequest,
+                GitError::NotFound => StatusCode::NotFound,
+                GitError::RefNotFound(_) => StatusCode::BadRequest,
+                GitError::NotFastForward => StatusCode::Conflict,
+                GitError::Lfs(_) | GitError::Git(_) | GitError::Io(_) => {
+                    StatusCode::InternalServerError
+                }
+            },
+            Self::GitHub(e) => match e {
+                GitHubError::AccessDenied => StatusCode::BadRequest,
+                _ => StatusCode::InternalServerError,
+            },
+            Self::Host(e) => match e {
+                HostError::AccessDenied => StatusCode::BadRequest,
+                _ => StatusCode::InternalServerError,
+            },
+            Self::Runner(e) => match e {
+                RunnerError::SecretsError(_) => StatusCode::InternalServerError,
+                _ => StatusCode::BadRequest,
+            },
+            Self::RepoConfig(_) => StatusCode::BadRequest,
+            Self::Io(_) => StatusCode::InternalServerError,
+        }
+    }
+
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::BadRequest(_) => "bad_request",
+            Self::Forbidden(_) => "forbidden",
+            Self::NotFound(_) => "not_found",
+            Self::Unauthorized(_) => "unauthorized",
+            Self::Git(e) => match e {
+                GitError::Exists => "repo_exists",
+                GitError::NotFound => "repo_not_found",
+                GitError::RefNotFound(_) => "ref_not_found",
+                GitError::NotFastForward => "not_fast_forward",
+                GitError::Lfs(_) => "lfs_error",
+                GitError::Git(_) => "git_error",
+                GitError::Io(_) => "io_error",
+            },
+            Self::GitHub(e) => github_code(e),
+            Self::Host(e) => match e {
+                HostError::NotFound => "host_not_found",
+                HostError::AccessDenied => "host_access_denied",
+                HostError::Http(_) => "host_error",
+                HostError::Io(_) => "io_error",
+                HostError::GitHub(e) => github_code(e),
+            },
+            Self::Runner(e) => match e {
+                RunnerError::CommandFailed(_) => "command_failed",
+                RunnerError::PrebuiltError(_) => "prebuilt_download_failed",
+                RunnerError::PrebuiltNotOnGitHub => "prebuilt_not_on_github",
+                RunnerError::SecretsError(_) => "secrets_error",
+                RunnerError::RepoConfigError(e) => repo_config_code(e),
+            },
+            Self::RepoConfig(e) => repo_config_code(e),
+            Self::Io(_) => "io_error",
+        }
+    }
+
+    fn message(&self) -> String {
+        match self {
+            Self::BadRequest(msg)
+            | Self::Forbidden(msg)
+            | Self::NotFound(msg)
+            | Self::Unauthorized(msg) => msg.clone(),
+            Self::Git(e) => match e {
+                GitError::Exists => "Error while cloning: already exists. Run with `force: true` to force re-reploy!".into(),
+                GitError::NotFound => "Error while cloning: repository not found!".into(),
+                GitError::RefNotFound(r) => format!("The repository has no branch, tag or commit named {}", r),
+                GitError::NotFastForward => "The branch was force-pushed, so the app wasn't updated. Set `on_force_push = \"reset\"` in pie.toml to follow force-pushes, or redeploy with `force: true`.".into(),
+                GitError::Lfs(_) => "Error while downloading the git lfs files! Is git-lfs installed on the server?".into(),
+                GitError::Git(_) => "Error while running git!".into(),
+                GitError::Io(_) => "Error while managing the repository directory! check the server logs".into(),
+            },
+            Self::GitHub(e) => github_message(e),
+            Self::Host(e) => match e {
+                HostError::NotFound => "Unable to reach the repo from the api of its git host.".into(),
+                HostError::AccessDenied => "The token configured for the git host can't manage the webhooks of the repository.".into(),
+                HostError::Http(_) => "Git host request failed with unknown error! check the server logs".into(),
+                HostError::Io(_) => "Unable to save the webhook secret! check the server logs".into(),
+                HostError::GitHub(e) => github_message(e),
+            },
+            Self::Runner(e) => match e {
+                RunnerError::CommandFailed(_) => "Error while running commands!".into(),
+                RunnerError::PrebuiltError(_) => "Unable to download the prebuilt asset from the latest GitHub release!".into(),
+                RunnerError::PrebuiltNotOnGitHub => "`prebuilt` only works for apps deployed from github.com, the asset is downloaded from the repo's GitHub releases. Use a build command instead.".into(),
+                RunnerError::SecretsError(_) => "Unable to read the secrets of the repo! check the server logs".into(),
+                RunnerError::RepoConfigError(e) => repo_config_message(e),
+            },
+            Self::RepoConfig(e) => repo_config_message(e),
+            Self::Io(_) => "Unknown error! check the server logs".into(),
+        }
+    }
+
+    fn details(&self) -> Option<String> {
+        match self {
+            Self::Git(GitError::Git(e)) => Some(e.message().to_string()),
+            Self::Git(GitError::Lfs(e)) => Some(e.clone()),
+            Self::Git(GitError::Io(e)) | Self::Io(e) => Some(e.to_string()),
+            Self::GitHub(e) | Self::Runner(RunnerError::PrebuiltError(e)) => github_details(e),
+            Self::Host(HostError::Http(e)) => Some(e.to_string()),
+            Self::Host(HostError::Io(e)) => Some(e.to_string()),
+            Self::Runner(RunnerError::CommandFailed(details)) => Some(details.clone()),
+            Self::Runner(RunnerError::SecretsError(e)) => Some(e.to_string()),
+            _ => None,
+        }
+    }
+
+    pub fn to_api_error(&self) -> ApiError {
+        ApiError {
+            code: self.code().into(),
+            message: self.message(),
+            details: self.details(),
+        }
+    }
+}
+
+fn repo_config_code(e: &RepoConfigError) -> &'static str {
+    match e {
+        RepoConfigError::InvalidTOML => "invalid_pie_toml",
+        RepoConfigError::MissingCommands => "missing_commands",
+    }
+}
+fn repo_config_message(e: &RepoConfigError) -> String {
+    match e {
+        RepoConfigError::InvalidTOML => "`pie.toml` is not a valid TOML file.".into(),
+        RepoConfigError::MissingCommands => "Your project type could not be auto-detected, and your pie.toml doesn't exist, or doesn't have a start command.".into(),
+    }
+}
+fn github_code(e: &GitHubError) -> &'static str {
+    match e {
+        GitHubError::NotFound => "github_not_found",
+        GitHubError::AccessDenied => "github_access_denied",
+        GitHubError::Http(_) => "github_error",
+        GitHubError::Io(_) => "io_error",
+    }
+}
+fn github_message(e: &GitHubError) -> String {
+    match e {
+        GitHubError::NotFound => "Unable to reach the repo or its release from the GitHub api.".into(),
+        GitHubError::AccessDenied => "You don't have write access to the git repository. This error usually occours when you deploy a public repo you don't own.".into(),
+        GitHubError::Http(_) => "GitHub request failed with unknown error! check the server logs".into(),
+        GitHubError::Io(_) => "Unable to save data from GitHub! check the server logs".into(),
+    }
+}
+fn github_details(e: &GitHubError) -> Option<String> {
+    match e {
+        GitHubError::Http(e) => Some(e.to_string()),
+        GitHubError::Io(e) => Some(e.to_string()),
+        _ => None,
+    }
+}
+
+impl fmt::Display for ServerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.details() {
+            Some(details) => write!(f, "{}: {}", self.message(), details),
+            None => write!(f, "{}", self.message()),
+        }
+    }
+}
+
+impl std::error::Error for ServerError {}
+
+impl From<GitError> for ServerError {
+    fn from(e: GitError) -> Self {
+        Self::Git(e)
+    }
+}
+impl From<GitHubError> for ServerError {
+    fn from(e: GitHubError) -> Self {
+        Self::GitHub(e)
+    }
+}
+impl From<HostError> for ServerError {
+    fn from(e: HostError) -> Self {
+        match e {
+            HostError::GitHub(e) => Self::GitHub(e),
+            e => Self::Host(e),
+        }
+    }
+}
+impl From<RunnerError> for ServerError {
+    fn from(e: RunnerError) -> Self {
+        match e {
+            RunnerError::RepoConfigError(e) => Self::RepoConfig(e),
+            e => Self::Runner(e),
+        }
+    }
+}
+impl From<RepoConfigError> for ServerError {
+    fn from(e: RepoConfigError) -> Self {
+        Self::RepoConfig(e)
+    }
+}
+impl From<std::io::Error> for ServerError {
+    fn from(e: std::io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+
+/// middleware turning every error a handler returns into a json `ApiError`
+pub async fn json_errors(mut res: Response) -> tide::Result {
+    let err = match res.take_error() {
+        Some(e) => e,
+        None => return Ok(res),
+    };
+
+    let (status, body) = match err.downcast_ref::<ServerError>() {
+        Some(e) => {
+            if e.status().is_server_error() {
+                error!("{:?}", e);
+            }
+            (e.status(), e.to_api_error())
+        }
+        // errors from tide itself, like a request body that isn't valid json
+        None => (
+            err.status(),
+            ApiError {
+                code: if err.status().is_server_error() {
+                    "internal_error".into()
+                } else {
+                    "bad_request".into()
+                },
+                message: err.to_string(),
+                details: None,
+            },
+        ),
+    };
+
+    res.set_status(status);
+    res.set_body(Body::from_json(&body)?);
+    Ok(res)
+}
@@ -1,84 +1,459 @@
-use crate::CONFIG;
-use async_std::{fs, path::PathBuf};
-use git2::{build::RepoBuilder, Cred, Error, ErrorCode, FetchOptions, RemoteCallbacks, Repository};
-use pie_lib::utils::{repo_to_path, repo_to_url};
+use crate::{deploy_key, github, provider::Provider};
+use async_std::{fs, path::PathBuf, prelude::*, process::Command, task};
+use git2::{
+    build::{CheckoutBuilder, RepoBuilder},
+    BranchType, CertificateCheckStatus, Commit, Cred, CredentialType, Error, ErrorCode,
+    FetchOptions, RemoteCallbacks, Repository, ResetType, Status, StatusOptions, Submodule,
+    SubmoduleUpdateOptions,
+};
+use pie_lib::{
+    config::{get_repo_config, ForcePushPolicy},
+    utils::{get_repos_dir, normalize_git_url, repo_to_deploy_key_file, repo_to_path, url_to_repo},
+};
+use std::time::Duration;
+use surf::Url;
 
+/// how often the cloned repos get gc'd
+const MAINTENANCE_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
+
+#[derive(Debug)]
 pub enum GitError {
     Exists,
     NotFound,
+    /// with the branch, tag or commit
+    RefNotFound(String),
+    /// the branch was force-pushed, and the app doesn't follow force-pushes
+    NotFastForward,
+    /// with the output of git-lfs
+    Lfs(String),
+    Git(Error),
+    Io(std::io::Error),
+}
+
+impl From<Error> for GitError {
+    fn from(e: Error) -> Self {
+        Self::Git(e)
+    }
 }
 
-pub async fn clone(repo: &str, force: bool) -> Result<(), GitError> {
+/// make room for a new clone of a repo, removing the old one if `force` is set
+pub async fn prepare(repo: &str, force: bool) -> Result<(), GitError> {
     let dirname = repo_to_path(repo);
 
     // directory exists
     let dir_exists = dirname.exists().await;
     if dir_exists && force {
-        match fs::remove_dir_all(&dirname).await {
-            Err(e) => panic!("{}", e),
-            Ok(x) => x,
-        };
+        if let Err(e) = fs::remove_dir_all(&dirname).await {
+            return Err(GitError::Io(e));
+        }
     } else if dir_exists {
         return Err(GitError::Exists);
     }
+    Ok(())
+}
 
-    let mut builder = RepoBuilder::new();
-    let mut callbacks = RemoteCallbacks::new();
-    let mut fetch_opts = FetchOptions::new();
+/// clone a repo, checking out `git_ref` instead of the default branch if it's given.
+/// with a `depth`, only that many commits of history are fetched
+pub async fn clone(
+    repo: &str,
+    url: &str,
+    git_ref: Option<&str>,
+    depth: Option<u32>,
+) -> Result<(), GitError> {
+    let dirname = repo_to_path(repo);
 
-    callbacks.credentials(|_, user, _| {
-        let creds = Cred::userpass_plaintext(user.unwrap_or(&CONFIG.gh_token), &CONFIG.gh_token);
+    {
+        let depth = depth.unwrap_or(0);
+        let mut fetch_opts = fetch_options();
+        fetch_opts.depth(depth as i32);
+        let mut builder = RepoBuilder::new();
+        builder.fetch_options(fetch_opts);
 
-        Ok(creds.unwrap())
-    });
-    fetch_opts.remote_callbacks(callbacks);
-    builder.fetch_options(fetch_opts);
-
-    return match builder.clone(
-        &repo_to_url(repo),
-        std::path::PathBuf::from(&dirname).as_path(),
-    ) {
-        Ok(_) => Ok(()),
-        Err(_) => Err(GitError::NotFound),
-    };
+        let repository = match builder.clone(url, std::path::PathBuf::from(&dirname).as_path()) {
+            Ok(r) => r,
+            Err(e) => {
+                error!("cloning {} failed: {}", url, e);
+                return Err(GitError::NotFound);
+            }
+        };
+
+        let git_ref = match git_ref {
+            Some(r) => {
+                checkout_ref(&repository, r, depth)?;
+                r.to_string()
+            }
+            None => get_current_branch_name(dirname)?,
+        };
+        repository.config()?.set_str("pie.ref", &git_ref)?;
+
+        update_submodules(&repository)?;
+    }
+
+    lfs_pull(repo).await
 }
 
-pub async fn pull(repo: &str) -> Result<(), Error> {
+fn checkout_ref(repo: &Repository, git_ref: &str, depth: u32) -> Result<(), GitError> {
+    let remote_branch = format!("origin/{}", git_ref);
+
+    // branches get a local branch following the remote one, so pushes to it can be pulled
+    if let Ok(branch) = repo.find_branch(&remote_branch, BranchType::Remote) {
+        let commit = branch.get().peel_to_commit()?;
+        let mut local = match repo.find_branch(git_ref, BranchType::Local) {
+            Ok(b) => b,
+            Err(_) => repo.branch(git_ref, &commit, false)?,
+        };
+        local.set_upstream(Some(&remote_branch))?;
+        repo.set_head(&format!("refs/heads/{}", git_ref))?;
+    } else {
+        // tags and commits are checked out as they are, pushes don't move them
+        let commit = match repo.revparse_single(git_ref) {
+            Ok(o) => o.peel_to_commit()?,
+            // older than the history a shallow clone has
+            Err(_) if repo.is_shallow() => fetch_ref(repo, git_ref, depth)?,
+            Err(_) => return Err(GitError::RefNotFound(git_ref.to_string())),
+        };
+        repo.set_head_detached(commit.id())?;
+    }
+
+    repo.checkout_head(Some(CheckoutBuilder::default().force()))?;
+    Ok(())
+}
+
+/// fetch a single tag or commit into a shallow clone
+fn fetch_ref<'a>(repo: &'a Repository, git_ref: &str, depth: u32) -> Result<Commit<'a>, GitError> {
+    let mut fetch_opts = fetch_options();
+    fetch_opts.depth(depth as i32);
+    let mut remote = repo.find_remote("origin")?;
+
+    for refspec in [
+        format!("+refs/tags/{0}:refs/tags/{0}", git_ref),
+        git_ref.to_string(),
+    ] {
+        if remote
+            .fetch(&[&refspec], Some(&mut fetch_opts), None)
+            .is_ok()
+        {
+            if let Ok(o) = repo.revparse_single(git_ref) {
+                return Ok(o.peel_to_commit()?);
+            }
+        }
+    }
+    Err(GitError::RefNotFound(git_ref.to_string()))
+}
+
+/// the checked out commit of a clone
+pub fn head_commit(repo: &str) -> Option<String> {
+    let repository = Repository::open(repo_to_path(repo)).ok()?;
+    let commit = repository.head().ok()?.peel_to_commit().ok()?;
+    Some(commit.id().to_string())
+}
+
+/// the url and ref a clone was deployed from
+pub fn origin(repo: &str) -> Option<(String, Option<String>)> {
+    let repository = Repository::open(repo_to_path(repo)).ok()?;
+    let url = repository.find_remote("origin").ok()?.url()?.to_string();
+    let git_ref = repository.config().ok()?.get_string("pie.ref").ok();
+    Some((url, git_ref))
+}
+
+/// the branch that pushes redeploy the app from, or `None` if a tag or commit was deployed
+pub fn tracked_branch(repo: &str) -> Result<Option<String>, GitError> {
+    let repository = Repository::open(repo_to_path(repo))?;
+    if repository.head_detached()? {
+        return Ok(None);
+    }
+    Ok(Some(get_current_branch_name(repo_to_path(repo))?))
+}
+
+pub async fn pull(repo: &str, on_force_push: ForcePushPolicy) -> Result<(), GitError> {
+    if fetch_and_update(repo, on_force_push)? {
+        lfs_pull(repo).await?;
+    }
+    Ok(())
+}
+
+/// the blocking part of `pull`, returning whether the checkout changed
+fn fetch_and_update(repo: &str, on_force_push: ForcePushPolicy) -> Result<bool, GitError> {
     let repo_dir = repo_to_path(repo);
     let repo = Repository::open(&repo_dir)?;
 
     info!("pulling repository: {:?}", repo.path());
 
+    // a deployed tag or commit stays where it is
+    if repo.head_detached()? {
+        info!("not on a branch, nothing to pull");
+        return Ok(false);
+    }
+
     let branch = &get_current_branch_name(repo_dir).unwrap_or("master".to_string());
     debug!("on branch: {:?}", branch);
 
-    let mut callbacks = RemoteCallbacks::new();
-    let mut fetch_opts = FetchOptions::new();
-
-    callbacks.credentials(|_, user, _| {
-        let creds = Cred::userpass_plaintext(user.unwrap_or(&CONFIG.gh_token), &CONFIG.gh_token);
-
-        Ok(creds.unwrap())
-    });
-    fetch_opts.remote_callbacks(callbacks);
-
-    repo.find_remote("origin")?
-        .fetch(&[branch], Some(&mut fetch_opts), None)?;
+    // a shallow clone fetches only the commits it doesn't have yet, with no depth.
+    // fetching with one would cut the history at the new commit, making every push look force-pushed
+    let mut remote = repo.find_remote("origin")?;
+    remote.fetch(&[branch], Some(&mut fetch_options()), None)?;
 
     let fetch_head = repo.find_reference("FETCH_HEAD")?;
     let fetch_commit = repo.reference_to_annotated_commit(&fetch_head)?;
     let analysis = repo.merge_analysis(&[&fetch_commit])?;
     if analysis.0.is_up_to_date() {
-        println!("up to date");
-        return Ok(());
+        info!("{} is up to date", branch);
+        return Ok(false);
     } else if analysis.0.is_fast_forward() {
         let refname = format!("refs/heads/{}", branch);
         let mut reference = repo.find_reference(&refname)?;
         reference.set_target(fetch_commit.id(), "Fast-Forward")?;
         repo.set_head(&refname)?;
-        return repo.checkout_head(Some(git2::build::CheckoutBuilder::default().force()));
+        repo.checkout_head(Some(CheckoutBuilder::default().force()))?;
+    } else if on_force_push == ForcePushPolicy::Reset {
+        info!("{} was force-pushed, resetting to it", branch);
+        let commit = repo.find_commit(fetch_commit.id())?;
+        repo.reset(
+            commit.as_object(),
+            ResetType::Hard,
+            Some(CheckoutBuilder::default().force()),
+        )?;
+        clean(&repo)?;
     } else {
-        return Err(Error::from_str("Fast-forward only!"));
+        return Err(GitError::NotFastForward);
+    }
+
+    update_submodules(&repo)?;
+    Ok(true)
+}
+
+/// init, update and check out every submodule of a repo, and the submodules of those
+fn update_submodules(repo: &Repository) -> Result<(), GitError> {
+    for mut submodule in repo.submodules()? {
+        debug!("updating submodule {:?}", submodule.path());
+
+        // follow url changes in .gitmodules
+        submodule.init(false)?;
+        submodule.sync()?;
+        fetch_over_https(repo, &submodule)?;
+
+        // libgit2 cleans up a failed clone of a submodule at its path relative to the current
+        // directory instead of the repo, deleting the files of whatever the server runs in.
+        // with the empty repo set up here, update only fetches into it
+        if submodule.open().is_err() {
+            submodule.repo_init(true)?;
+        }
+
+        let mut checkout = CheckoutBuilder::new();
+        checkout.force();
+        let mut opts = SubmoduleUpdateOptions::new();
+        opts.fetch(fetch_options()).checkout(checkout);
+        submodule.update(true, Some(&mut opts))?;
+
+        update_submodules(&submodule.open()?)?;
+    }
+
+    Ok(())
+}
+
+/// relative submodule urls of a repo cloned with its deploy key resolve to ssh urls of other
+/// repos, which the key can't open. those are fetched over https with the host's token instead
+fn fetch_over_https(repo: &Repository, submodule: &Submodule) -> Result<(), GitError> {
+    let key = match submodule.name() {
+        Some(name) => format!("submodule.{}.url", name),
+        None => return Ok(()),
+    };
+    let url = match repo.config()?.get_string(&key) {
+        Ok(url) => url,
+        Err(_) => return Ok(()),
+    };
+    let https = match ssh_to_https(&url) {
+        Some(https) => https,
+        None => return Ok(()),
+    };
+    // another deployed app, which has a deploy key of its own
+    if url_to_repo(&https).is_some_and(|r| deploy_key::get(&r).is_some()) {
+        return Ok(());
+    }
+
+    debug!("fetching submodule {} from {}", &url, &https);
+    repo.config()?.set_str(&key, &https)?;
+    // already cloned, and fetched from its own origin
+    if let Ok(sub) = submodule.open() {
+        sub.remote_set_url("origin", &https)?;
+    }
+    Ok(())
+}
+
+/// the https url of an ssh or scp-like url
+fn ssh_to_https(url: &str) -> Option<String> {
+    match Url::parse(url) {
+        Ok(u) if u.scheme() != "ssh" => None,
+        _ => normalize_git_url(url),
+    }
+}
+
+/// replace the git lfs pointers of a repo with the files, if its pie.toml asks for it.
+/// libgit2 can't do this, so it needs git-lfs installed
+async fn lfs_pull(repo: &str) -> Result<(), GitError> {
+    let repo_dir = repo_to_path(repo);
+    let lfs = get_repo_config(repo_dir.clone())
+        .await
+        .ok()
+        .and_then(|c| c.lfs)
+        .unwrap_or(false);
+    if !lfs {
+        return Ok(());
+    }
+
+    info!("pulling the lfs files of {}", repo);
+    let mut cmd = Command::new("git");
+    cmd.args(["lfs", "pull"])
+        .current_dir(&repo_dir)
+        .env("GIT_TERMINAL_PROMPT", "0");
+
+    let origin = Repository::open(&repo_dir)?
+        .find_remote("origin")?
+        .url()
+        .map(String::from);
+    let provider = origin.as_deref().and_then(Provider::from_url);
+    if origin.as_deref().is_some_and(|u| u.starts_with("ssh://")) {
+        // cloned with the deploy key, which git-lfs has to hand to ssh itself
+        let known_hosts = deploy_key::known_hosts_file().await.map_err(GitError::Io)?;
+        cmd.env(
+            "GIT_SSH_COMMAND",
+            format!(
+                "ssh -i '{}' -o IdentitiesOnly=yes -o BatchMode=yes -o UserKnownHostsFile='{}'",
+                repo_to_deploy_key_file(repo).to_string_lossy(),
+                known_hosts.to_string_lossy()
+            ),
+        );
+    } else if let Some((user, pass)) = provider.as_ref().and_then(|p| p.userpass(None)) {
+        // the host's token, passed in the environment so it doesn't show up in `ps`
+        let auth = base64::encode(format!("{}:{}", user, pass));
+        cmd.env("GIT_CONFIG_COUNT", "1")
+            .env("GIT_CONFIG_KEY_0", "http.extraHeader")
+            .env(
+                "GIT_CONFIG_VALUE_0",
+                format!("Authorization: Basic {}", auth),
+            );
+    }
+
+    let out = cmd.output().await.map_err(GitError::Io)?;
+    if !out.status.success() {
+        return Err(GitError::Lfs(
+            String::from_utf8_lossy(&out.stderr).trim().to_string(),
+        ));
+    }
+    Ok(())
+}
+
+/// gc every cloned repo, then once every `MAINTENANCE_INTERVAL`
+pub async fn maintenance() {
+    loop {
+        if let Err(e) = gc_repos(get_repos_dir()).await {
+            error!("repo maintenance failed: {}", e);
+        }
+        task::sleep(MAINTENANCE_INTERVAL).await;
+    }
+}
+
+/// drop the history that resets and force-pushes left behind, and pack the rest,
+/// so clones take as little space as they can. uploads aren't git repos and are skipped
+async fn gc_repos(dir: PathBuf) -> Result<(), std::io::Error> {
+    if !dir.is_dir().await {
+        return Ok(());
+    }
+
+    let mut owners = fs::read_dir(&dir).await?;
+    while let Some(owner) = owners.next().await {
+        let owner = owner?.path();
+        let mut repos = fs::read_dir(&owner).await?;
+        let mut empty = true;
+        while let Some(repo) = repos.next().await {
+            empty = false;
+            let repo = repo?.path();
+            if !repo.join(".git").is_dir().await {
+                continue;
+            }
+
+            debug!("running gc in {:?}", &repo);
+            for args in [
+                vec!["reflog", "expire", "--expire=now", "--all"],
+                // objects written in the last hour might belong to a running fetch
+                vec!["gc", "--quiet", "--prune=1.hour.ago"],
+            ] {
+                let out = Command::new("git")
+                    .args(&args)
+                    .current_dir(&repo)
+                    .output()
+                    .await?;
+                if !out.status.success() {
+                    warn!(
+                        "`git {}` failed in {:?}: {}",
+                        args.join(" "),
+                        &repo,
+                        String::from_utf8_lossy(&out.stderr).trim()
+                    );
+                    break;
+                }
+            }
+        }
+        // left behind when the last app of an owner was replaced or removed
+        if empty {
+            fs::remove_dir(&owner).await?;
+        }
+    }
+
+    info!("repo maintenance done");
+    Ok(())
+}
+
+/// remove every file git doesn't track, ignored ones included, like `git clean -fdx`
+fn clean(repo: &Repository) -> Result<(), GitError> {
+    let workdir = match repo.workdir() {
+        Some(w) => w,
+        None => return Ok(()),
+    };
+
+    let mut opts = StatusOptions::new();
+    opts.include_untracked(true)
+        .include_ignored(true)
+        .recurse_untracked_dirs(false);
+    for entry in repo.statuses(Some(&mut opts))?.iter() {
+        if !entry.status().intersects(Status::WT_NEW | Status::IGNORED) {
+            continue;
+        }
+        let path = match entry.path() {
+            Some(p) => workdir.join(p),
+            None => continue,
+        };
+        debug!("removing untracked {:?}", &path);
+
+        let res = if path.is_dir() {
+            std::fs::remove_dir_all(&path)
+        } else {
+            std::fs::remove_file(&path)
+        };
+        res.map_err(GitError::Io)?;
+    }
+
+    Ok(())
+}
+
+/// fetch options authenticating to the host of whatever url is fetched, so submodules
+/// from other hosts get their own token
+fn fetch_options<'a>() -> FetchOptions<'a> {
+    let mut callbacks = RemoteCallbacks::new();
+    callbacks.credentials(credentials);
+    callbacks.certificate_check(|cert, host| match host {
+        "github.com" => github::check_host_key(cert),
+        _ => Ok(CertificateCheckStatus::CertificatePassthrough),
+    });
+    let mut fetch_opts = FetchOptions::new();
+    fetch_opts.remote_callbacks(callbacks);
+    fetch_opts
+}
+
+fn credentials(url: &str, user: Option<&str>, allowed: CredentialType) -> Result<Cred, Error> {
+    match Provider::from_url(url) {
+        Some(p) => p.credentials(user, allowed),
+        None => Err(Error::from_str("unknown git host")),
     }
 }
 
@@ -95,3 +470,23 @@ fn get_current_branch_name(repo_dir: PathBuf) -> Result<String, Error> {
     let branch = head.unwrap_or("master");
     Ok(branch.to_owned())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn submodule_https_urls() {
+        assert_eq!(
+            ssh_to_https("ssh://git@github.com/owner/lib.git"),
+            Some("https://github.com/owner/lib.git".into())
+        );
+        assert_eq!(
+            ssh_to_https("git@github.com:owner/lib.git"),
+            Some("https://github.com/owner/lib.git".into())
+        );
+        assert_eq!(ssh_to_https("https://github.com/owner/lib.git"), None);
+        assert_eq!(ssh_to_https("git://git.lan/owner/lib.git"), None);
+        assert_eq!(ssh_to_https("/srv/git/owner/lib.git"), None);
+    }
+}
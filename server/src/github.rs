@@ -1,11 +1,10 @@
-use crate::{git, runner, CONFIG, PROJECT_DIRS};
-use async_std::{
-    fs,
-    path::{Path, PathBuf},
-};
-use pie_lib::utils::{get_unix_time, split_repo};
+use crate::{deploy_key, provider::webhook_url, webhook, CONFIG};
+use async_std::{fs, path::PathBuf};
+use git2::{cert::Cert, CertificateCheckStatus};
+use pie_lib::utils::split_repo;
 use serde::{Deserialize, Serialize};
 use serde_json::json;
+use std::os::unix::fs::PermissionsExt;
 use surf::{Client, Error, StatusCode, Url};
 
 lazy_static! {
@@ -19,17 +18,50 @@ lazy_static! {
         .unwrap();
 }
 
+/// sha256 fingerprints of github.com's ssh host keys, from
+/// https://docs.github.com/en/authentication/keeping-your-account-and-data-secure/githubs-ssh-key-fingerprints
+const SSH_HOST_KEYS: [&str; 3] = [
+    "+DiY3wvvV6TuJJhbpZisF/zLDA0zPMSvHdkr4UvCOqU",
+    "p2QAMXNIC1TJYWeIOttrVc98/R1BUFWu3/LiyKgUfQM",
+    "uNiVztksCsDhcc0u9e8BujQXVUpKZIDTMczCvj3tD2s",
+];
+
+/// the same keys in the known_hosts format, for the ssh that git-lfs runs
+pub const KNOWN_HOSTS: &str = "\
+github.com ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIOMqqnkVzrm0SdG6UOoqKLsabgH5C9okWi0dh2l9GKJl
+github.com ecdsa-sha2-nistp256 AAAAE2VjZHNhLXNoYTItbmlzdHAyNTYAAAAIbmlzdHAyNTYAAABBBEmKSENjQEezOmxkZMy7opKgwFB9nkt5YRrYMjNuG5N87uRgg6CLrbo5wAdT/y6v0mKV0U2w0WZ2YB/++Tpockg=
+github.com ssh-rsa AAAAB3NzaC1yc2EAAAADAQABAAABgQCj7ndNxQowgcQnjshcLrqPEiiphnt+VTTvDP6mHBL9j1aNUkY4Ue1gvwnGLVlOhGeYrnZaMgRK6+PKCUXaDbC7qtbW8gIkhL7aGCsOr/C56SJMy/BCZfxd1nWzAOxSDPgVsmerOBYfNqltV9/hWCqBywINIR+5dIg6JTJ72pcEpEjcYgXkE2YEFXV1JHnsKgbLWNlhScqb2UmyRkQyytRLtL+38TGxkxCflmO+5Z8CSSNY7GidjMIZ7Q4zMjA2n1nGrlTDkzwDCsw+wqFPGQA179cnfGWOWRVruj16z6XyvxvjJwbz0wQZ75XK5tKSb7FNyeIEs4TT4jk+S4dhPeAUC5y+bDYirYgM4GC7uEnztnZyaVWQ7B381AK4Qdrwt51ZqExKbQpTUNn+EjqoTwvqNj4kqx5QUCI0ThS/YkOxJCXmPUWZbhjpCg56i+2aB6CmK2JGhn57K5mj0MNdBXA4/WnwH6XoPWJzK5Nyu2zB3nAZp+S5hpQs+p1vN1/wsjk=
+";
+
+/// for the certificate check callback of git2, when connecting to github.com over ssh
+pub fn check_host_key(cert: &Cert) -> Result<CertificateCheckStatus, git2::Error> {
+    let hostkey = match cert.as_hostkey() {
+        Some(k) => k,
+        None => return Ok(CertificateCheckStatus::CertificatePassthrough),
+    };
+
+    let known = hostkey.hash_sha256().is_some_and(|hash| {
+        SSH_HOST_KEYS
+            .iter()
+            .any(|k| base64::decode_config(k, base64::STANDARD_NO_PAD).is_ok_and(|k| k == hash))
+    });
+    if known {
+        Ok(CertificateCheckStatus::CertificateOk)
+    } else {
+        Err(git2::Error::from_str(
+            "the ssh host key of github.com doesn't match",
+        ))
+    }
+}
+
 #[derive(Debug)]
 pub enum GitHubError {
     NotFound,
     AccessDenied,
     Http(Error),
+    Io(std::io::Error),
 }
 
-#[derive(Serialize, Deserialize, Debug)]
-struct GitHubHookBody {
-    repository: GitHubRepository,
-}
 #[derive(Serialize, Deserialize, Debug)]
 struct GitHubRepository {
     full_name: String,
@@ -44,98 +76,256 @@ async fn get_repo(repo_name: &str) -> Result<GitHubRepository, GitHubError> {
         .send()
         .await;
 
-    if res.is_err() {
-        return Err(GitHubError::Http(res.unwrap_err()));
-    }
-    let mut res = res.unwrap();
+    let mut res = match res {
+        Ok(res) => res,
+        Err(e) => return Err(GitHubError::Http(e)),
+    };
 
     if res.status() == StatusCode::NotFound {
         return Err(GitHubError::NotFound);
     }
 
-    let data = res.body_json::<GitHubRepository>().await;
+    match res.body_json::<GitHubRepository>().await {
+        Ok(data) => Ok(data),
+        Err(e) => Err(GitHubError::Http(e)),
+    }
+}
+
+pub async fn init_repo(repo_name: &str) -> Result<(), GitHubError> {
+    let r = split_repo(repo_name);
+
+    let _repo = get_repo(repo_name).await?;
+
+    add_deploy_key(repo_name).await?;
+
+    // saved before creating the hook, since github sends a ping right away
+    let secret = webhook::get_or_create_secret(repo_name)
+        .await
+        .map_err(GitHubError::Io)?;
 
-    if data.is_err() {
-        return Err(GitHubError::Http(data.unwrap_err()));
+    let url = webhook_url();
+    let j = json!({"name": "web", "active": true, "events": ["push"], "config": {"url": &url, "content_type": "json", "secret": &secret}});
+
+    // the hook of an earlier deploy is updated, github refuses a second one with the same url
+    let hooks: Vec<GitHubHook> = list(&format!("/repos/{}/{}/hooks", r.0, r.1)).await?;
+    let req = match hooks.iter().find(|h| h.config.url.as_ref() == Some(&url)) {
+        Some(hook) => CLIENT.patch(format!("/repos/{}/{}/hooks/{}", r.0, r.1, hook.id)),
+        None => CLIENT.post(format!("/repos/{}/{}/hooks", r.0, r.1)),
+    };
+
+    let res = req.body(j).send().await.map_err(|e| {
+        error!("creating the webhook of {} failed: {}", repo_name, &e);
+        GitHubError::Http(e)
+    })?;
+    match res.status() {
+        s if s.is_success() => Ok(()),
+        StatusCode::NotFound | StatusCode::Forbidden => Err(GitHubError::AccessDenied),
+        s => Err(GitHubError::Http(Error::from_str(
+            s,
+            "creating the webhook failed",
+        ))),
     }
-    let data = data.unwrap();
+}
+
+/// register the repo's ssh key as a read-only deploy key, which pie clones and pulls with
+async fn add_deploy_key(repo_name: &str) -> Result<(), GitHubError> {
+    let r = split_repo(repo_name);
+    let key = deploy_key::get_or_create(repo_name)
+        .await
+        .map_err(GitHubError::Io)?;
 
-    Ok(data)
+    let res = CLIENT
+        .post(format!("/repos/{owner}/{repo}/keys", owner = r.0, repo = r.1))
+        .body(json!({"title": format!("pie ({})", &CONFIG.url), "key": key.trim(), "read_only": true}))
+        .send()
+        .await
+        .map_err(GitHubError::Http)?;
+
+    match res.status() {
+        s if s.is_success() => Ok(()),
+        // the key is already registered, from an earlier deploy
+        StatusCode::UnprocessableEntity => Ok(()),
+        StatusCode::NotFound | StatusCode::Forbidden => Err(GitHubError::AccessDenied),
+        s => Err(GitHubError::Http(Error::from_str(
+            s,
+            "registering the deploy key failed",
+        ))),
+    }
 }
 
-pub async fn init_repo(repo_name: &str) -> Result<(), GitHubError> {
+#[derive(Deserialize, Debug)]
+struct GitHubHook {
+    id: u64,
+    config: GitHubHookConfig,
+}
+#[derive(Deserialize, Debug)]
+struct GitHubHookConfig {
+    url: Option<String>,
+}
+#[derive(Deserialize, Debug)]
+struct GitHubKey {
+    id: u64,
+    key: String,
+}
+
+/// remove the webhook and the deploy key `init_repo` registered
+pub async fn deinit_repo(repo_name: &str) -> Result<(), GitHubError> {
     let r = split_repo(repo_name);
+    let url = webhook_url();
 
-    let repo = get_repo(repo_name).await;
-    if repo.is_err() {
-        return Err(repo.unwrap_err());
+    let hooks: Vec<GitHubHook> = list(&format!("/repos/{}/{}/hooks", r.0, r.1)).await?;
+    for hook in hooks.iter().filter(|h| h.config.url.as_ref() == Some(&url)) {
+        delete(&format!("/repos/{}/{}/hooks/{}", r.0, r.1, hook.id)).await?;
     }
-    let _repo = repo?;
 
-    let j = json!({"name": "web", "config": {"url": String::from(&CONFIG.url) + "/handler", "content_type": "json"}});
+    // github leaves the comment out of the keys, so only the type and the key itself are compared
+    if let Some((public, _)) = deploy_key::get(repo_name) {
+        let public = public.split_whitespace().take(2).collect::<Vec<_>>();
+        let keys: Vec<GitHubKey> = list(&format!("/repos/{}/{}/keys", r.0, r.1)).await?;
+        for key in keys
+            .iter()
+            .filter(|k| k.key.split_whitespace().take(2).eq(public.iter().copied()))
+        {
+            delete(&format!("/repos/{}/{}/keys/{}", r.0, r.1, key.id)).await?;
+        }
+    }
+
+    Ok(())
+}
+
+async fn list<T: serde::de::DeserializeOwned>(path: &str) -> Result<Vec<T>, GitHubError> {
+    let mut res = CLIENT
+        .get(path)
+        .query(&json!({"per_page": 100}))
+        .map_err(GitHubError::Http)?
+        .send()
+        .await
+        .map_err(GitHubError::Http)?;
+    match res.status() {
+        s if s.is_success() => res.body_json().await.map_err(GitHubError::Http),
+        StatusCode::NotFound | StatusCode::Forbidden => Err(GitHubError::AccessDenied),
+        s => Err(GitHubError::Http(Error::from_str(
+            s,
+            format!("GET {} failed", path),
+        ))),
+    }
+}
 
+async fn delete(path: &str) -> Result<(), GitHubError> {
     let res = CLIENT
-        .post(format!(
-            "/repos/{owner}/{repo}/hooks",
+        .delete(path)
+        .send()
+        .await
+        .map_err(GitHubError::Http)?;
+    match res.status() {
+        // already deleted
+        s if s.is_success() || s == StatusCode::NotFound => Ok(()),
+        StatusCode::Forbidden => Err(GitHubError::AccessDenied),
+        s => Err(GitHubError::Http(Error::from_str(
+            s,
+            format!("DELETE {} failed", path),
+        ))),
+    }
+}
+
+#[derive(Deserialize, Debug)]
+struct GitHubRelease {
+    tag_name: String,
+    assets: Vec<GitHubAsset>,
+}
+#[derive(Deserialize, Debug)]
+struct GitHubAsset {
+    name: String,
+    url: String,
+}
+
+/// download an asset of the latest release of a repo, and make it executable
+pub async fn download_release_asset(
+    repo_name: &str,
+    asset_name: &str,
+    dest: PathBuf,
+) -> Result<(), GitHubError> {
+    let r = split_repo(repo_name);
+
+    let mut res = CLIENT
+        .get(format!(
+            "/repos/{owner}/{repo}/releases/latest",
             owner = r.0,
             repo = r.1
         ))
-        .body(j)
         .send()
-        .await;
+        .await
+        .map_err(GitHubError::Http)?;
+    if res.status() == StatusCode::NotFound {
+        return Err(GitHubError::NotFound);
+    }
+    let release = res
+        .body_json::<GitHubRelease>()
+        .await
+        .map_err(GitHubError::Http)?;
 
-    match res {
-        Ok(r) => {
-            if r.status() == StatusCode::NotFound {
-                Err(GitHubError::AccessDenied)
-            } else {
-                Ok(())
-            }
-        }
-        Err(e) => {
-            dbg!(&e);
-            Err(GitHubError::Http(e))
+    let asset = match release.assets.iter().find(|a| a.name == asset_name) {
+        Some(a) => a,
+        None => {
+            warn!(
+                "release {} of {} has no asset named {}",
+                &release.tag_name, repo_name, asset_name
+            );
+            return Err(GitHubError::NotFound);
         }
+    };
+    info!(
+        "downloading {} from release {} of {}",
+        &asset.name, &release.tag_name, repo_name
+    );
+
+    let mut res = CLIENT
+        .get(&asset.url)
+        .header("Accept", "application/octet-stream")
+        .send()
+        .await
+        .map_err(GitHubError::Http)?;
+    // the asset itself is served from elsewhere, which doesn't want our github token
+    if res.status().is_redirection() {
+        let location = match res.header("Location") {
+            Some(l) => l.as_str().to_string(),
+            None => return Err(GitHubError::NotFound),
+        };
+        res = surf::get(location)
+            .send()
+            .await
+            .map_err(GitHubError::Http)?;
+    }
+    if !res.status().is_success() {
+        return Err(GitHubError::NotFound);
+    }
+    let bytes = res.body_bytes().await.map_err(GitHubError::Http)?;
+
+    if let Some(dir) = dest.parent() {
+        fs::create_dir_all(dir).await.map_err(GitHubError::Io)?;
     }
+    fs::write(&dest, bytes).await.map_err(GitHubError::Io)?;
+    fs::set_permissions(&dest, std::fs::Permissions::from_mode(0o755))
+        .await
+        .map_err(GitHubError::Io)
 }
 
-pub async fn webhook_handler(mut req: tide::Request<()>) -> tide::Result {
-    let req_body = req.body_string().await?;
-    let hook_event = req.header("X-GitHub-Event").unwrap().as_str();
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sha2::{Digest, Sha256};
 
-    // write hook body to a file
-    let mut hooks_folder = PathBuf::from(PROJECT_DIRS.data_local_dir());
-    hooks_folder.push("hooks");
-    if !hooks_folder.is_dir().await {
-        fs::create_dir_all(&hooks_folder).await?;
+    #[test]
+    fn known_hosts_match_the_fingerprints() {
+        // a fingerprint is the sha256 of the key itself, without the host and the type
+        let fingerprints = KNOWN_HOSTS
+            .lines()
+            .map(|line| {
+                let key = line.split_whitespace().nth(2).unwrap();
+                let hash = Sha256::digest(base64::decode(key).unwrap());
+                base64::encode_config(hash, base64::STANDARD_NO_PAD)
+            })
+            .collect::<Vec<String>>();
+        assert_eq!(fingerprints, SSH_HOST_KEYS);
     }
-    let hook_id = String::from(get_unix_time().to_string());
-    fs::write(
-        Path::join(
-            &hooks_folder.as_path(),
-            format!("{date}-{event}.json", date = hook_id, event = hook_event),
-        ),
-        &req_body,
-    )
-    .await?;
-
-    return match hook_event {
-        "ping" => Ok("pong".into()),
-        "push" => {
-            println!("push");
-
-            let body: GitHubHookBody = match serde_json::from_str(&req_body) {
-                Ok(b) => b,
-                Err(e) => panic!("{}", e),
-            };
-
-            git::pull(&body.repository.full_name).await.unwrap();
-
-            runner::run(&body.repository.full_name).await.unwrap();
-
-            return Ok("pull successful".into());
-        }
-
-        _ => Ok(tide::Response::builder(404).body("event not found").build()),
-    };
 }
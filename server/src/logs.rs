@@ -0,0 +1,157 @@
+use async_std::{
+    fs::{self, File},
+    io::{prelude::*, SeekFrom},
+    path::PathBuf,
+    task,
+};
+use std::{io::ErrorKind, time::Duration};
+use tide::{sse::Sender, Request, Response};
+
+// the log files of apps, streamed to the api as server-sent events
+
+pub const DEFAULT_TAIL: usize = 100;
+/// how much of the file is read at once, while looking for the last lines
+const BLOCK_SIZE: u64 = 8 * 1024;
+const FOLLOW_INTERVAL: Duration = Duration::from_millis(500);
+/// a client that went away is only noticed when sending to it, so idle logs get a `ping` now and then
+const KEEPALIVE: Duration = Duration::from_secs(15);
+
+/// send the last `tail` lines of a log file, and the lines written to it afterwards if `follow` is set
+pub fn stream(req: Request<()>, path: PathBuf, tail: usize, follow: bool) -> Response {
+    tide::sse::upgrade(req, move |_req, sender| {
+        let path = path.clone();
+        async move {
+            // following only ends when the client goes away, which isn't an error
+            if let Err(e) = send_lines(&sender, &path, tail, follow).await {
+                debug!("stopped sending {:?}: {}", &path, e);
+            }
+            Ok(())
+        }
+    })
+}
+
+async fn send_lines(
+    sender: &Sender,
+    path: &PathBuf,
+    tail: usize,
+    follow: bool,
+) -> Result<(), std::io::Error> {
+    let (lines, mut offset) = last_lines(path, tail, follow).await?;
+    for line in lines {
+        sender.send("log", line, None).await?;
+    }
+    if !follow {
+        return Ok(());
+    }
+
+    let mut idle = Duration::ZERO;
+    loop {
+        task::sleep(FOLLOW_INTERVAL).await;
+        let lines = match new_lines(path, &mut offset).await {
+            Ok(lines) => lines,
+            // removed along with the app, or not written yet after a restart
+            Err(e) if e.kind() == ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e),
+        };
+
+        if lines.is_empty() {
+            idle += FOLLOW_INTERVAL;
+            if idle >= KEEPALIVE {
+                sender.send("ping", "", None).await?;
+                idle = Duration::ZERO;
+            }
+            continue;
+        }
+        idle = Duration::ZERO;
+        for line in lines {
+            sender.send("log", line, None).await?;
+        }
+    }
+}
+
+/// the last `n` lines of a file, and the offset to follow it from. only the end of the file
+/// is read, a block at a time. a line still being written is left for following it
+async fn last_lines(
+    path: &PathBuf,
+    n: usize,
+    follow: bool,
+) -> Result<(Vec<String>, u64), std::io::Error> {
+    let mut file = File::open(path).await?;
+    let len = file.metadata().await?.len();
+
+    let mut start = len;
+    let mut buf = Vec::new();
+    while start > 0 && buf.iter().filter(|b| **b == b'\n').count() <= n {
+        let size = BLOCK_SIZE.min(start);
+        start -= size;
+        file.seek(SeekFrom::Start(start)).await?;
+        let mut block = vec![0; size as usize];
+        file.read_exact(&mut block).await?;
+        block.append(&mut buf);
+        buf = block;
+    }
+
+    let (mut lines, read) = split_lines(&buf, !follow);
+    let lines = lines.split_off(lines.len().saturating_sub(n));
+    Ok((lines, start + read as u64))
+}
+
+/// the complete lines written to a file after `offset`, moving `offset` past them
+async fn new_lines(path: &PathBuf, offset: &mut u64) -> Result<Vec<String>, std::io::Error> {
+    let len = fs::metadata(path).await?.len();
+    // truncated, or replaced by a new log
+    if len < *offset {
+        *offset = 0;
+    }
+    if len == *offset {
+        return Ok(Vec::new());
+    }
+
+    let mut file = File::open(path).await?;
+    file.seek(SeekFrom::Start(*offset)).await?;
+    let mut buf = Vec::new();
+    file.read_to_end(&mut buf).await?;
+
+    let (lines, read) = split_lines(&buf, false);
+    *offset += read as u64;
+    Ok(lines)
+}
+
+/// the lines of `buf` and how many bytes they took up. an unfinished line at the end is only
+/// included with `partial`, so the rest of it can be read later
+fn split_lines(buf: &[u8], partial: bool) -> (Vec<String>, usize) {
+    let end = match buf.iter().rposition(|b| *b == b'\n') {
+        Some(i) => i + 1,
+        None => 0,
+    };
+    let mut lines = buf[..end]
+        .split(|b| *b == b'\n')
+        .map(|l| String::from_utf8_lossy(l.strip_suffix(b"\r").unwrap_or(l)).into_owned())
+        .collect::<Vec<_>>();
+    // the empty piece after the last newline
+    lines.pop();
+
+    if partial && end < buf.len() {
+        lines.push(String::from_utf8_lossy(&buf[end..]).into_owned());
+        return (lines, buf.len());
+    }
+    (lines, end)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lines() {
+        assert_eq!(
+            split_lines(b"one\r\ntwo\n\nthr", false),
+            (vec!["one".into(), "two".into(), "".into()], 10)
+        );
+        assert_eq!(
+            split_lines(b"one\nthr", true),
+            (vec!["one".into(), "thr".into()], 7)
+        );
+        assert_eq!(split_lines(b"", true), (vec![], 0));
+    }
+}
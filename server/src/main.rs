@@ -2,20 +2,38 @@
 extern crate lazy_static;
 #[macro_use]
 extern crate log;
-use crate::{git::GitError, github::GitHubError};
+use crate::{
+    error::{json_errors, ServerError},
+    progress::Progress,
+    provider::Provider,
+};
 use directories::ProjectDirs;
 use pie_lib::{
-    config::{get_server_config, RepoConfigError, ServerConfig},
-    utils::{create_logs_dir, string_to_cmd_and_args, url_to_repo},
-};
-use runner::RunnerError;
-use tide::{
-    prelude::{Deserialize, Serialize},
-    Redirect, Request, Response, Result,
+    api::{App, AppInfo, DeployParams, LogsParams, ProcessStatus, UploadParams},
+    config::{get_repo_config, get_server_config, RepoConfig, ServerConfig, WEB_PROCESS},
+    utils::{
+        create_deploy_keys_dir, create_logs_dir, create_secrets_dir, create_webhook_secrets_dir,
+        is_valid_repo_part, normalize_git_url, repo_process_to_log_file, repo_to_deploy_key_file,
+        repo_to_log_file, repo_to_path, repo_to_webhook_secret_file, string_to_cmd_and_args,
+        url_to_repo,
+    },
 };
+use surf::Url;
+use tide::{Redirect, Request, Response, Result};
+mod auth;
+mod deploy_key;
+mod error;
 mod git;
 mod github;
+mod logs;
+mod progress;
+mod provider;
+mod registry;
 mod runner;
+mod secrets;
+mod supervisor;
+mod upload;
+mod webhook;
 
 //* GET /ping
 async fn ping(_req: Request<()>) -> Result {
@@ -23,75 +41,522 @@ async fn ping(_req: Request<()>) -> Result {
 }
 
 //* POST /handler
-// this endpoint is for github webhooks, and not the user
+// this endpoint is for git host webhooks, and not the user
 async fn handler(req: Request<()>) -> Result {
-    if req.header("X-GitHub-Event").is_none() {
-        return Ok(Response::builder(418)
-            .body("this endpoint is reserved for GitHub webhooks")
-            .build());
-    }
+    webhook::handler(req).await
+}
 
-    github::webhook_handler(req).await
+//* GET /auth
+// lets the cli check an api token before saving it
+async fn auth(_req: Request<()>) -> Result {
+    Ok("Authenticated!".into())
 }
 
 //* POST /deploy
-#[derive(Debug, Deserialize, Serialize)]
-pub struct DeployParams {
-    repo: String,
-    force: Option<bool>,
-}
+// the progress of the deploy is streamed back as server-sent events
 async fn deploy(mut req: Request<()>) -> Result {
     let params: DeployParams = req.body_json().await?;
 
-    let repo = url_to_repo(&params.repo);
-    if repo.is_none() {
-        return Ok(Response::builder(400)
-            .body("The `repo` parameter is not a url to a valid GitHub repository")
-            .build());
+    let (repo, url) = match url_to_repo(&params.repo).zip(normalize_git_url(&params.repo)) {
+        Some(r) => r,
+        None => {
+            return Err(ServerError::BadRequest(
+                "The `repo` parameter is not a valid git url of an `owner/name` repo".into(),
+            )
+            .into())
+        }
+    };
+    let source = Source::Git {
+        url,
+        git_ref: params.git_ref,
+        depth: params.depth.or(CONFIG.depth),
+        force: params.force.unwrap_or(false),
+    };
+
+    Ok(stream_deploy(req, repo, source))
+}
+
+//* POST /upload/:owner/:name
+// deploy a tarball of a local working tree, instead of cloning the repo
+async fn upload(mut req: Request<()>) -> Result {
+    let repo = repo_param(&req)?;
+    let params: UploadParams = req.query()?;
+
+    if !params.force.unwrap_or(false) && upload::is_cloned(&repo).await {
+        return Err(ServerError::BadRequest(format!(
+            "{} was deployed from its git remote. Run with `force: true` to replace it with the upload!",
+            &repo
+        ))
+        .into());
     }
-    let repo = repo.unwrap();
 
-    // clone the repo
-    let clone = git::clone(&repo, params.force.unwrap_or(false)).await;
-    if clone.is_err() {
-        return Ok(match clone.unwrap_err() {
-            GitError::Exists => Response::builder(400).body("Error while cloning: already exists. Run with `force: true` to force re-reploy!").build(),
-            GitError::NotFound => Response::builder(404).body("Error while cloning: repository not found!").build(),
-        });
+    let archive = req.body_bytes().await?;
+    Ok(stream_deploy(req, repo, Source::Upload(archive)))
+}
+
+/// where the code of a deploy comes from
+#[derive(Clone)]
+enum Source {
+    Git {
+        url: String,
+        git_ref: Option<String>,
+        depth: Option<u32>,
+        force: bool,
+    },
+    Upload(Vec<u8>),
+}
+
+/// run a deploy in the background, streaming its progress back as server-sent events
+fn stream_deploy(req: Request<()>, repo: String, source: Source) -> Response {
+    tide::sse::upgrade(req, move |_req, sender| {
+        let repo = repo.clone();
+        let source = source.clone();
+        async move {
+            let progress = Progress::new(&repo, Some(sender));
+
+            match deploy_repo(&repo, source, &progress).await {
+                Ok(_) => {
+                    let status = supervisor::status(&repo).await;
+                    progress
+                        .event("done", &serde_json::to_string(&status)?)
+                        .await;
+                }
+                Err(e) => {
+                    error!("deploying {} failed: {}", &repo, e);
+                    let err = serde_json::to_string(&e.to_api_error())?;
+                    progress.event("error", &err).await;
+                }
+            }
+            Ok(())
+        }
+    })
+}
+
+/// deploy an app, keeping its entry in the registry up to date
+async fn deploy_repo(
+    repo: &str,
+    source: Source,
+    progress: &Progress,
+) -> std::result::Result<(), ServerError> {
+    let _lock = registry::lock(repo).await;
+
+    // a deploy that would replace an app without `force` leaves its entry alone
+    if let Source::Git { url, force, .. } = &source {
+        check_host(repo, url).await?;
+        git::prepare(repo, *force).await?;
+    }
+
+    registry::deploying(repo, |app| {
+        // deploying an app starts it again, if it was stopped
+        app.stopped = false;
+        match &source {
+            Source::Git {
+                url,
+                git_ref,
+                depth,
+                ..
+            } => {
+                app.url = Some(url.clone());
+                app.git_ref = git_ref.clone();
+                app.depth = *depth;
+            }
+            Source::Upload(_) => {
+                app.url = None;
+                app.git_ref = None;
+                app.depth = None;
+            }
+        }
+    })
+    .await;
+
+    let res = deploy_source(repo, source, progress).await;
+    registry::deployed(repo, res.as_ref().err().map(|e| e.to_string())).await;
+    res
+}
+
+/// apps are only told apart by `owner/name`, so the same name from another git host
+/// would take over the app's clone, secrets and webhook. it has to be destroyed first
+async fn check_host(repo: &str, url: &str) -> std::result::Result<(), ServerError> {
+    let host = |u: &str| {
+        Url::parse(u)
+            .ok()
+            .and_then(|u| u.host_str().map(String::from))
     };
 
-    // create github webhook
-    let gh = github::init_repo(&repo).await;
-    if gh.is_err() {
-        return Ok(match gh.unwrap_err() {
-            GitHubError::NotFound => Response::builder(500)
-                .body("unable to reach the repo from the github api.\nat this point, cloning the repo was successful, this error should never occour")
-                .build(),
-            GitHubError::AccessDenied=>Response::builder(400).body("You don't have write access to the git repository. This error usually occours when you deploy a public repo you don't own.").build(),
-            GitHubError::Http(err) => {
-                error!("github request failed with unknown error: {:?}", &err);
-                Response::builder(500)
-                    .body("unknown error! check the server logs")
-                    .build()
-            },
+    match registry::get(repo).await.and_then(|app| app.url) {
+        Some(deployed) if host(&deployed) != host(url) => Err(ServerError::BadRequest(format!(
+            "{} is already deployed from {}. Destroy it first to deploy it from {}",
+            repo, deployed, url
+        ))),
+        _ => Ok(()),
+    }
+}
+
+async fn deploy_source(
+    repo: &str,
+    source: Source,
+    progress: &Progress,
+) -> std::result::Result<(), ServerError> {
+    match source {
+        Source::Git {
+            url,
+            git_ref,
+            depth,
+            ..
+        } => {
+            // before cloning, since github repos are cloned with the deploy key it registers
+            let url = match Provider::from_url(&url) {
+                Some(provider) if provider.has_webhooks() => {
+                    progress
+                        .send(&format!("creating the webhook on {}", &provider.host))
+                        .await;
+                    provider.init_repo(repo).await?;
+                    if provider.has_deploy_keys() {
+                        provider.ssh_url()
+                    } else {
+                        url
+                    }
+                }
+                _ => {
+                    progress
+                        .send("no webhook for this host, redeploy to update the app")
+                        .await;
+                    url
+                }
+            };
+
+            progress.send("cloning the repository").await;
+            git::clone(repo, &url, git_ref.as_deref(), depth).await?;
+        }
+        Source::Upload(archive) => {
+            progress.send("unpacking the upload").await;
+            upload::unpack(repo, archive).await.map_err(|e| {
+                ServerError::BadRequest(format!("Unable to unpack the upload: {}", e))
+            })?;
+        }
+    }
+
+    runner::run(repo, progress).await?;
+    Ok(())
+}
+
+/// the `owner/name` of the route. they end up in paths on disk, so only sane names are allowed
+fn repo_param(req: &Request<()>) -> Result<String> {
+    let owner = req.param("owner")?;
+    let name = req.param("name")?;
+
+    for part in [owner, name] {
+        if !is_valid_repo_part(part) {
+            return Err(
+                ServerError::BadRequest(format!("{:?} is not a valid app name", part)).into(),
+            );
+        }
+    }
+
+    Ok(format!("{}/{}", owner, name))
+}
+
+//* GET /status/:owner/:name
+async fn status(req: Request<()>) -> Result {
+    let repo = repo_param(&req)?;
+
+    let status = supervisor::status(&repo).await;
+    if status.is_empty() {
+        return Err(ServerError::NotFound(format!("{} is not running", &repo)).into());
+    }
+
+    Ok(Response::builder(200)
+        .body(tide::Body::from_json(&status)?)
+        .build())
+}
+
+//* GET /apps
+async fn list_apps(_req: Request<()>) -> Result {
+    let mut apps = Vec::new();
+    for app in registry::list().await {
+        let process_status = supervisor::status(&app.repo).await;
+        apps.push(AppInfo {
+            app,
+            process_status,
         });
+    }
+
+    Ok(Response::builder(200)
+        .body(tide::Body::from_json(&apps)?)
+        .build())
+}
+
+//* GET /apps/:owner/:name
+async fn app_info(req: Request<()>) -> Result {
+    let repo = repo_param(&req)?;
+
+    let app = match registry::get(&repo).await {
+        Some(app) => app,
+        None => return Err(ServerError::NotFound(format!("{} is not deployed", &repo)).into()),
     };
+    let process_status = supervisor::status(&repo).await;
 
-    // run the code
-    let run = runner::run(&repo).await;
-    if run.is_err() {
-        return Ok(match run.unwrap_err() {
-            RunnerError::CommandFailed => Response::builder(400)
-                .body("Error while running commands!")
-                .build(),
-            RunnerError::RepoConfigError(e) => match e{
-                RepoConfigError::InvalidTOML => Response::builder(400).body("`pie.toml` is not a valid TOML file.").build(),
-                RepoConfigError::MissingCommands => Response::builder(400).body("Your project type could not be auto-detected, and your pie.toml doesn't exist, or doesn't have a start command.").build(),
-            },
-        });
+    Ok(Response::builder(200)
+        .body(tide::Body::from_json(&AppInfo {
+            app,
+            process_status,
+        })?)
+        .build())
+}
+
+//* DELETE /apps/:owner/:name
+// stops the app, and removes its webhook and everything the server keeps of it, except its secrets
+async fn destroy_app(req: Request<()>) -> Result {
+    let repo = repo_param(&req)?;
+
+    let app = match registry::get(&repo).await {
+        Some(app) => app,
+        None => return Err(ServerError::NotFound(format!("{} is not deployed", &repo)).into()),
+    };
+    let _lock = match registry::try_lock(&repo).await {
+        Some(lock) => lock,
+        None => {
+            return Err(ServerError::BadRequest(format!(
+                "{} is being deployed, try again once the deploy is done",
+                &repo
+            ))
+            .into())
+        }
+    };
+
+    // an app with a broken pie.toml is still stopped, with the default signal
+    let repo_config = get_repo_config(repo_to_path(&repo))
+        .await
+        .unwrap_or_default();
+    runner::stop(&repo, &repo_config)
+        .await
+        .map_err(ServerError::from)?;
+    supervisor::forget(&repo).await;
+
+    // the app is destroyed either way, a host that can't be reached only keeps its webhook
+    let mut msg = format!("Destroyed {}", &repo);
+    if let Some(provider) = app
+        .url
+        .as_deref()
+        .and_then(Provider::from_url)
+        .filter(|p| p.has_webhooks())
+    {
+        if let Err(e) = provider.deinit_repo(&repo).await {
+            let e = ServerError::from(e);
+            warn!("removing the webhook of {} failed: {}", &repo, &e);
+            msg = format!(
+                "{}, but removing its webhook from {} failed: {}",
+                msg, &provider.host, e
+            );
+        }
+    }
+
+    remove_app_files(&app).await.map_err(ServerError::from)?;
+    registry::remove(&repo).await.map_err(ServerError::from)?;
+
+    info!("destroyed {}", &repo);
+    Ok(msg.into())
+}
+
+/// what a request to control a deployed app does with it
+#[derive(Clone, Copy, PartialEq)]
+enum Control {
+    Start,
+    Stop,
+    Restart,
+}
+
+//* POST /apps/:owner/:name/start
+async fn start_app(req: Request<()>) -> Result {
+    control_app(&req, Control::Start).await
+}
+
+//* POST /apps/:owner/:name/stop
+// the app stays stopped through pushes and restarts of the server, until it's started or deployed
+async fn stop_app(req: Request<()>) -> Result {
+    control_app(&req, Control::Stop).await
+}
+
+//* POST /apps/:owner/:name/restart
+async fn restart_app(req: Request<()>) -> Result {
+    control_app(&req, Control::Restart).await
+}
+
+/// start or stop the processes of an app, and respond with their status
+async fn control_app(req: &Request<()>, control: Control) -> Result {
+    let repo = repo_param(req)?;
+
+    if registry::get(&repo).await.is_none() {
+        return Err(ServerError::NotFound(format!("{} is not deployed", &repo)).into());
+    }
+    let _lock = match registry::try_lock(&repo).await {
+        Some(lock) => lock,
+        None => {
+            return Err(ServerError::BadRequest(format!(
+                "{} is being deployed, try again once the deploy is done",
+                &repo
+            ))
+            .into())
+        }
+    };
+
+    let repo_config = match get_repo_config(repo_to_path(&repo)).await {
+        Ok(c) => c,
+        // an app with a broken pie.toml can still be stopped, with the default signal
+        Err(_) if control == Control::Stop => RepoConfig::default(),
+        Err(e) => return Err(ServerError::from(e).into()),
+    };
+
+    if control == Control::Start {
+        let running = supervisor::status(&repo)
+            .await
+            .values()
+            .any(|p| matches!(p, ProcessStatus::Running { .. }));
+        if running {
+            return Err(ServerError::BadRequest(format!(
+                "{} is already running, restart it instead",
+                &repo
+            ))
+            .into());
+        }
+    } else {
+        runner::stop(&repo, &repo_config)
+            .await
+            .map_err(ServerError::from)?;
+    }
+
+    registry::update(&repo, |app| app.stopped = control == Control::Stop)
+        .await
+        .map_err(ServerError::from)?;
+
+    if control != Control::Stop {
+        runner::start(&repo, &repo_config)
+            .await
+            .map_err(ServerError::from)?;
+    }
+
+    let status = supervisor::status(&repo).await;
+    Ok(Response::builder(200)
+        .body(tide::Body::from_json(&status)?)
+        .build())
+}
+
+//* GET /apps/:owner/:name/logs
+// the lines are streamed back as server-sent events
+async fn app_logs(req: Request<()>) -> Result {
+    let repo = repo_param(&req)?;
+    let params: LogsParams = req.query()?;
+
+    let app = match registry::get(&repo).await {
+        Some(app) => app,
+        None => return Err(ServerError::NotFound(format!("{} is not deployed", &repo)).into()),
+    };
+    // checked against the app, since it ends up in the path of the log file
+    let process = params.process.unwrap_or_else(|| WEB_PROCESS.to_string());
+    if process != WEB_PROCESS && !app.processes.contains_key(&process) {
+        return Err(
+            ServerError::NotFound(format!("{} has no process named {}", &repo, &process)).into(),
+        );
+    }
+
+    let path = repo_process_to_log_file(&repo, &process);
+    if !path.is_file().await {
+        return Err(ServerError::NotFound(format!("{} has no logs yet", &repo)).into());
+    }
+
+    Ok(logs::stream(
+        req,
+        path,
+        params.tail.unwrap_or(logs::DEFAULT_TAIL),
+        params.follow.unwrap_or(false),
+    ))
+}
+
+/// the clone, logs, webhook secret and deploy key of an app.
+/// its secrets are kept, for when it's deployed again
+async fn remove_app_files(app: &App) -> std::io::Result<()> {
+    let repo = &app.repo;
+    let mut files = vec![
+        repo_to_log_file(repo),
+        repo_to_webhook_secret_file(repo),
+        repo_to_deploy_key_file(repo),
+        deploy_key::public_key_file(repo),
+    ];
+    files.extend(
+        app.processes
+            .keys()
+            .map(|process| repo_process_to_log_file(repo, process)),
+    );
+
+    for file in files {
+        match async_std::fs::remove_file(&file).await {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e),
+            _ => {}
+        }
     }
 
-    Ok(format!("Successfully cloned {}", &params.repo).into())
+    let dir = repo_to_path(repo);
+    if dir.exists().await {
+        async_std::fs::remove_dir_all(&dir).await?;
+    }
+    Ok(())
+}
+
+//* GET /secrets/:owner/:name
+// secret values are masked, they can't be read back
+async fn list_secrets(req: Request<()>) -> Result {
+    let repo = repo_param(&req)?;
+
+    let masked = secrets::get(&repo)
+        .await
+        .map_err(ServerError::from)?
+        .into_iter()
+        .map(|(k, v)| (k, secrets::mask(&v)))
+        .collect::<std::collections::BTreeMap<_, _>>();
+
+    Ok(Response::builder(200)
+        .body(tide::Body::from_json(&masked)?)
+        .build())
+}
+
+//* PUT /secrets/:owner/:name/:key
+// the body is the value of the secret
+async fn set_secret(mut req: Request<()>) -> Result {
+    let repo = repo_param(&req)?;
+    let key = req.param("key")?.to_string();
+    if !secrets::is_valid_key(&key) {
+        return Err(ServerError::BadRequest(format!(
+            "{:?} is not a valid secret name, use letters, digits and _ and don't start with a digit",
+            &key
+        ))
+        .into());
+    }
+    let value = req.body_string().await?;
+
+    secrets::set(&repo, &key, &value)
+        .await
+        .map_err(ServerError::from)?;
+
+    Ok(format!("Secret {} set for {}, redeploy to apply it", &key, &repo).into())
+}
+
+//* DELETE /secrets/:owner/:name/:key
+async fn unset_secret(req: Request<()>) -> Result {
+    let repo = repo_param(&req)?;
+    let key = req.param("key")?;
+
+    if secrets::unset(&repo, key)
+        .await
+        .map_err(ServerError::from)?
+    {
+        Ok(format!(
+            "Secret {} removed from {}, redeploy to apply it",
+            key, &repo
+        )
+        .into())
+    } else {
+        Err(ServerError::NotFound(format!("{} has no secret named {}", &repo, key)).into())
+    }
 }
 
 //* POST /exec
@@ -99,7 +564,13 @@ async fn deploy(mut req: Request<()>) -> Result {
 async fn exec(mut req: Request<()>) -> Result {
     let cmd = req.body_string().await?;
     let c = string_to_cmd_and_args(&cmd);
-    let res = runner::exec(c.0, c.1, async_std::path::PathBuf::from("/tmp")).await;
+    let res = runner::exec(
+        c.0,
+        c.1,
+        async_std::path::PathBuf::from("/tmp"),
+        &Default::default(),
+    )
+    .await;
     match res {
         Ok(s) => Ok(s.into()),
         Err(e) => Ok(e.to_string().into()),
@@ -119,17 +590,47 @@ async fn main() -> Result<()> {
 
     // server directories setup
     create_logs_dir().expect("unable to create logs dir");
+    create_webhook_secrets_dir().expect("unable to create webhook secrets dir");
+    create_secrets_dir().expect("unable to create secrets dir");
+    create_deploy_keys_dir().expect("unable to create deploy keys dir");
+
+    registry::init()
+        .await
+        .expect("unable to load the app registry");
+    async_std::task::spawn(runner::resume_apps());
+    async_std::task::spawn(git::maintenance());
 
     // setup http server
     let mut app = tide::new();
     app.with(driftwood::DevLogger);
+    app.with(tide::utils::After(json_errors));
     app.at("/")
         .get(Redirect::new("https://github.com/beni69/pie"));
     app.at("/ping").get(ping);
+    // public, but only accepts requests signed with the webhook secret
     app.at("/handler").all(handler);
-    app.at("/deploy").post(deploy);
-    app.at("/exec").post(exec);
-    let host = format!("127.0.0.1:{}", &CONFIG.port.unwrap());
+
+    // the management api, every route needs an api token
+    let mut api = app.at("/");
+    api.with(auth::TokenAuth);
+    api.at("auth").get(auth);
+    api.at("deploy").post(deploy);
+    api.at("upload/:owner/:name").post(upload);
+    api.at("status/:owner/:name").get(status);
+    api.at("apps").get(list_apps);
+    api.at("apps/:owner/:name")
+        .get(app_info)
+        .delete(destroy_app);
+    api.at("apps/:owner/:name/start").post(start_app);
+    api.at("apps/:owner/:name/stop").post(stop_app);
+    api.at("apps/:owner/:name/restart").post(restart_app);
+    api.at("apps/:owner/:name/logs").get(app_logs);
+    api.at("secrets/:owner/:name").get(list_secrets);
+    api.at("secrets/:owner/:name/:key")
+        .put(set_secret)
+        .delete(unset_secret);
+    api.at("exec").post(exec);
+    let host = format!("127.0.0.1:{}", CONFIG.port.unwrap_or(6969));
     app.listen(&host).await?;
 
     Ok(())
@@ -0,0 +1,32 @@
+use tide::sse::Sender;
+
+/// reports the steps of a deploy. they are always logged,
+/// and sent as server-sent events when the deploy was started from the api
+#[derive(Debug)]
+pub struct Progress {
+    repo: String,
+    sender: Option<Sender>,
+}
+
+impl Progress {
+    pub fn new(repo: &str, sender: Option<Sender>) -> Self {
+        Self {
+            repo: repo.to_string(),
+            sender,
+        }
+    }
+
+    pub async fn send(&self, msg: &str) {
+        info!("{}: {}", &self.repo, msg);
+        self.event("progress", msg).await;
+    }
+
+    /// a closed connection doesn't stop the deploy, the client just stops getting updates
+    pub async fn event(&self, name: &str, data: &str) {
+        if let Some(sender) = &self.sender {
+            if let Err(e) = sender.send(name, data, None).await {
+                debug!("unable to send {} event for {}: {}", name, &self.repo, e);
+            }
+        }
+    }
+}
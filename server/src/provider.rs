@@ -0,0 +1,252 @@
+use crate::{
+    deploy_key,
+    github::{self, GitHubError},
+    webhook, CONFIG,
+};
+use git2::{Cred, CredentialType, Error};
+use pie_lib::config::HostKind;
+use serde_json::{json, Value};
+use surf::{StatusCode, Url};
+
+// the git host a repo is cloned from, and what pie can do with it
+
+#[derive(Debug)]
+pub enum HostError {
+    GitHub(GitHubError),
+    NotFound,
+    AccessDenied,
+    Http(surf::Error),
+    Io(std::io::Error),
+}
+
+#[derive(Debug, Clone)]
+pub struct Provider {
+    pub kind: HostKind,
+    pub host: String,
+    token: Option<String>,
+    api_url: String,
+    /// the path of the repo on the host, like `owner/name`
+    path: String,
+}
+
+impl Provider {
+    /// the host of a repo url, as configured in the `[hosts]` of the server config.
+    /// github.com and gitlab.com are known without configuring them
+    pub fn from_url(url: &str) -> Option<Self> {
+        let u = Url::parse(url).ok()?;
+        let host = u.host_str()?.to_string();
+        let path = u.path().trim_matches('/');
+        let path = path.strip_suffix(".git").unwrap_or(path).to_string();
+
+        let (kind, token, api_url) = match CONFIG.hosts.get(&host) {
+            Some(h) => (h.kind, h.token.clone(), h.api_url.clone()),
+            None if host == "github.com" => (HostKind::GitHub, Some(CONFIG.gh_token.clone()), None),
+            None if host == "gitlab.com" => (HostKind::GitLab, None, None),
+            None => (HostKind::Git, None, None),
+        };
+        let api_url = api_url.unwrap_or_else(|| match u.port() {
+            Some(port) => format!("{}://{}:{}", u.scheme(), &host, port),
+            None => format!("{}://{}", u.scheme(), &host),
+        });
+
+        Some(Self {
+            kind,
+            host,
+            token,
+            api_url: api_url.trim_end_matches('/').to_string(),
+            path,
+        })
+    }
+
+    /// for the credentials callback of git2. ssh remotes use the repo's deploy key,
+    /// https ones the token of the host
+    pub fn credentials(&self, user: Option<&str>, allowed: CredentialType) -> Result<Cred, Error> {
+        if allowed.contains(CredentialType::SSH_KEY) {
+            return match deploy_key::get(&self.path) {
+                Some((public, private)) => {
+                    Cred::ssh_key_from_memory(user.unwrap_or("git"), Some(&public), &private, None)
+                }
+                None => Err(Error::from_str(&format!(
+                    "{} has no deploy key",
+                    &self.path
+                ))),
+            };
+        }
+
+        match self.userpass(user) {
+            Some((user, pass)) => Cred::userpass_plaintext(user, pass),
+            None => Err(Error::from_str(&format!(
+                "no token configured for {}",
+                &self.host
+            ))),
+        }
+    }
+
+    /// the username and password git authenticates to the host with over https
+    pub fn userpass<'a>(&'a self, user: Option<&'a str>) -> Option<(&'a str, &'a str)> {
+        let token = self.token.as_deref()?;
+
+        Some(match self.kind {
+            HostKind::GitHub => (user.unwrap_or(token), token),
+            HostKind::GitLab => ("oauth2", token),
+            // gitea takes the token as the password of any user
+            HostKind::Gitea | HostKind::Git => (user.unwrap_or("pie"), token),
+        })
+    }
+
+    /// whether the repo is cloned with a deploy key, registered by `init_repo`
+    pub fn has_deploy_keys(&self) -> bool {
+        self.kind == HostKind::GitHub && self.token.is_some()
+    }
+
+    /// the url to clone the repo with its deploy key
+    pub fn ssh_url(&self) -> String {
+        format!("ssh://git@{}/{}.git", &self.host, &self.path)
+    }
+
+    /// whether pushes to the repo can be redeployed with a webhook
+    pub fn has_webhooks(&self) -> bool {
+        self.kind != HostKind::Git && self.token.is_some()
+    }
+
+    /// register the webhook that redeploys the repo on pushes, and the deploy key if the host has them
+    pub async fn init_repo(&self, repo: &str) -> Result<(), HostError> {
+        let (hooks_url, header) = match self.kind {
+            HostKind::GitHub => return github::init_repo(repo).await.map_err(HostError::GitHub),
+            _ => match self.hooks_api() {
+                Some(api) => api,
+                None => return Ok(()),
+            },
+        };
+
+        // saved before creating the hook, in case the host sends an event right away
+        let secret = webhook::get_or_create_secret(repo)
+            .await
+            .map_err(HostError::Io)?;
+        let body = match self.kind {
+            HostKind::Gitea => json!({
+                "type": "gitea",
+                "active": true,
+                "events": ["push"],
+                "config": {"url": webhook_url(), "content_type": "json", "secret": secret},
+            }),
+            _ => json!({"url": webhook_url(), "push_events": true, "token": secret}),
+        };
+
+        // the hook of an earlier deploy is updated instead of adding another one,
+        // and any extra ones are removed
+        let hooks = self.own_hooks(&hooks_url, &header).await?;
+        let req = match hooks.split_first() {
+            Some((id, extra)) => {
+                for id in extra {
+                    self.delete_hook(&hooks_url, &header, id).await?;
+                }
+                let url = format!("{}/{}", &hooks_url, id);
+                match self.kind {
+                    HostKind::Gitea => surf::patch(url),
+                    _ => surf::put(url),
+                }
+            }
+            None => surf::post(&hooks_url),
+        };
+
+        let res = req
+            .header(header.0, header.1.as_str())
+            .body(body)
+            .send()
+            .await
+            .map_err(HostError::Http)?;
+        self.check_status(res.status(), "creating the webhook")
+    }
+
+    /// remove what `init_repo` registered on the host, when the app is destroyed
+    pub async fn deinit_repo(&self, repo: &str) -> Result<(), HostError> {
+        let (hooks_url, header) = match self.kind {
+            HostKind::GitHub => return github::deinit_repo(repo).await.map_err(HostError::GitHub),
+            _ => match self.hooks_api() {
+                Some(api) => api,
+                None => return Ok(()),
+            },
+        };
+
+        for id in self.own_hooks(&hooks_url, &header).await? {
+            self.delete_hook(&hooks_url, &header, &id).await?;
+        }
+        Ok(())
+    }
+
+    /// the api url of the repo's webhooks on gitea and gitlab, and the header to authenticate with
+    fn hooks_api(&self) -> Option<(String, (&'static str, String))> {
+        let token = self.token.as_deref().unwrap_or_default();
+
+        match self.kind {
+            HostKind::Gitea => Some((
+                format!("{}/api/v1/repos/{}/hooks", &self.api_url, &self.path),
+                ("Authorization", format!("token {}", token)),
+            )),
+            HostKind::GitLab => Some((
+                format!(
+                    "{}/api/v4/projects/{}/hooks",
+                    &self.api_url,
+                    self.path.replace('/', "%2F")
+                ),
+                ("PRIVATE-TOKEN", token.to_string()),
+            )),
+            HostKind::GitHub | HostKind::Git => None,
+        }
+    }
+
+    /// the ids of the repo's webhooks that point to this server
+    async fn own_hooks(
+        &self,
+        hooks_url: &str,
+        header: &(&str, String),
+    ) -> Result<Vec<Value>, HostError> {
+        let mut res = surf::get(hooks_url)
+            .header(header.0, header.1.as_str())
+            .send()
+            .await
+            .map_err(HostError::Http)?;
+        self.check_status(res.status(), "listing the webhooks")?;
+        let hooks: Vec<Value> = res.body_json().await.map_err(HostError::Http)?;
+
+        let url = webhook_url();
+        Ok(hooks
+            .into_iter()
+            // gitea has the url in the config of the hook, gitlab on the hook itself
+            .filter(|hook| hook["config"]["url"].as_str().or(hook["url"].as_str()) == Some(&url))
+            .map(|hook| hook["id"].clone())
+            .collect())
+    }
+
+    async fn delete_hook(
+        &self,
+        hooks_url: &str,
+        header: &(&str, String),
+        id: &Value,
+    ) -> Result<(), HostError> {
+        let res = surf::delete(format!("{}/{}", hooks_url, id))
+            .header(header.0, header.1.as_str())
+            .send()
+            .await
+            .map_err(HostError::Http)?;
+        self.check_status(res.status(), "deleting the webhook")
+    }
+
+    fn check_status(&self, status: StatusCode, action: &str) -> Result<(), HostError> {
+        match status {
+            s if s.is_success() => Ok(()),
+            StatusCode::NotFound => Err(HostError::NotFound),
+            StatusCode::Unauthorized | StatusCode::Forbidden => Err(HostError::AccessDenied),
+            s => Err(HostError::Http(surf::Error::from_str(
+                s,
+                format!("{} on {} failed", action, &self.host),
+            ))),
+        }
+    }
+}
+
+/// the url of the `/handler` route, which the webhooks are sent to
+pub fn webhook_url() -> String {
+    format!("{}/handler", CONFIG.url.trim_end_matches('/'))
+}
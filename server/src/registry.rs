@@ -0,0 +1,212 @@
+use crate::git;
+use async_std::{
+    fs,
+    prelude::*,
+    sync::{Arc, Mutex, MutexGuardArc},
+};
+use pie_lib::{
+    api::{App, DeployStatus},
+    config::get_repo_config,
+    utils::{get_registry_file, get_repos_dir, get_unix_time, repo_to_path},
+};
+use std::{
+    collections::{BTreeMap, HashMap},
+    io::{Error, ErrorKind},
+};
+
+// every deployed app, kept in a json file so it survives restarts.
+// written whenever a deploy starts and ends, through /deploy, /upload or a webhook
+
+lazy_static! {
+    static ref APPS: Mutex<BTreeMap<String, App>> = Mutex::new(BTreeMap::new());
+    static ref LOCKS: Mutex<HashMap<String, Arc<Mutex<()>>>> = Mutex::new(HashMap::new());
+}
+
+/// held while an app is stopped, built or started, so deploys, webhooks and
+/// start/stop requests of the same app don't run into each other
+pub async fn lock(repo: &str) -> MutexGuardArc<()> {
+    app_lock(repo).await.lock_arc().await
+}
+
+/// the lock of an app, unless something else holds it already
+pub async fn try_lock(repo: &str) -> Option<MutexGuardArc<()>> {
+    app_lock(repo).await.try_lock_arc()
+}
+
+async fn app_lock(repo: &str) -> Arc<Mutex<()>> {
+    LOCKS
+        .lock()
+        .await
+        .entry(repo.to_string())
+        .or_default()
+        .clone()
+}
+
+/// load the registry from disk. deploys that were running when the server stopped are marked failed
+pub async fn init() -> Result<(), Error> {
+    let path = get_registry_file();
+    let mut apps = match fs::read_to_string(&path).await {
+        Ok(f) => serde_json::from_str(&f).map_err(|e| Error::new(ErrorKind::InvalidData, e))?,
+        // the first start with a registry, so the apps deployed before it are added
+        Err(e) if e.kind() == ErrorKind::NotFound => import().await?,
+        Err(e) => return Err(e),
+    };
+
+    for app in apps.values_mut() {
+        if app.status == DeployStatus::Deploying {
+            app.status = DeployStatus::Failed;
+            app.error = Some("the server stopped during the deploy".into());
+        }
+    }
+
+    save(&apps).await?;
+    info!("loaded {} apps from the registry", apps.len());
+    *APPS.lock().await = apps;
+    Ok(())
+}
+
+/// the apps already on disk, from before the registry existed
+async fn import() -> Result<BTreeMap<String, App>, Error> {
+    let mut apps = BTreeMap::new();
+    let dir = get_repos_dir();
+    if !dir.is_dir().await {
+        return Ok(apps);
+    }
+
+    let mut owners = fs::read_dir(&dir).await?;
+    while let Some(owner) = owners.next().await {
+        let owner = owner?;
+        let mut names = fs::read_dir(owner.path()).await?;
+        while let Some(name) = names.next().await {
+            let name = name?;
+            let repo = format!(
+                "{}/{}",
+                owner.file_name().to_string_lossy(),
+                name.file_name().to_string_lossy()
+            );
+            let (url, git_ref) = match git::origin(&repo) {
+                Some((url, git_ref)) => (Some(url), git_ref),
+                None => (None, None),
+            };
+            let deployed_at = name
+                .metadata()
+                .await
+                .and_then(|m| m.modified())
+                .ok()
+                .and_then(|t| t.duration_since(std::time::UNIX_EPOCH).ok())
+                .map(|d| d.as_millis() as u64)
+                .unwrap_or_default();
+
+            info!("adding {} to the registry", &repo);
+            let mut app = new_app(&repo);
+            app.url = url;
+            app.git_ref = git_ref;
+            app.commit = git::head_commit(&repo);
+            app.processes = processes(&repo).await;
+            app.status = DeployStatus::Deployed;
+            app.deployed_at = deployed_at;
+            apps.insert(repo, app);
+        }
+    }
+
+    Ok(apps)
+}
+
+/// written to a temporary file first, so a crash can't leave half of it behind
+async fn save(apps: &BTreeMap<String, App>) -> Result<(), Error> {
+    let path = get_registry_file();
+    let tmp = path.with_extension("json.tmp");
+    let file = serde_json::to_string_pretty(apps).map_err(Error::other)?;
+
+    fs::write(&tmp, file).await?;
+    fs::rename(&tmp, &path).await
+}
+
+fn new_app(repo: &str) -> App {
+    App {
+        repo: repo.to_string(),
+        url: None,
+        git_ref: None,
+        depth: None,
+        commit: None,
+        processes: BTreeMap::new(),
+        status: DeployStatus::Deploying,
+        error: None,
+        deployed_at: get_unix_time() as u64,
+        stopped: false,
+    }
+}
+
+async fn processes(repo: &str) -> BTreeMap<String, String> {
+    get_repo_config(repo_to_path(repo))
+        .await
+        .map(|c| c.processes)
+        .unwrap_or_default()
+}
+
+pub async fn list() -> Vec<App> {
+    APPS.lock().await.values().cloned().collect()
+}
+
+pub async fn get(repo: &str) -> Option<App> {
+    APPS.lock().await.get(repo).cloned()
+}
+
+pub async fn remove(repo: &str) -> Result<(), Error> {
+    let mut apps = APPS.lock().await;
+    if apps.remove(repo).is_some() {
+        save(&apps).await?;
+    }
+    Ok(())
+}
+
+/// change an app, adding it if it isn't in the registry yet
+pub async fn update(repo: &str, f: impl FnOnce(&mut App)) -> Result<App, Error> {
+    let mut apps = APPS.lock().await;
+    let app = apps
+        .entry(repo.to_string())
+        .or_insert_with(|| new_app(repo));
+    f(app);
+    let app = app.clone();
+    save(&apps).await?;
+    Ok(app)
+}
+
+/// record that a deploy of an app started, with `f` setting where it's deployed from
+pub async fn deploying(repo: &str, f: impl FnOnce(&mut App)) {
+    let res = update(repo, |app| {
+        app.status = DeployStatus::Deploying;
+        app.error = None;
+        f(app);
+    })
+    .await;
+    if let Err(e) = res {
+        error!("saving the registry failed: {}", e);
+    }
+}
+
+/// record how a deploy of an app ended, along with what got deployed
+pub async fn deployed(repo: &str, error: Option<String>) {
+    let commit = git::head_commit(repo);
+    let git_ref = git::origin(repo).and_then(|(_, git_ref)| git_ref);
+    let processes = processes(repo).await;
+
+    let res = update(repo, |app| {
+        app.status = match error {
+            Some(_) => DeployStatus::Failed,
+            None => DeployStatus::Deployed,
+        };
+        app.error = error;
+        app.commit = commit;
+        // the default branch, once it's known
+        if app.git_ref.is_none() {
+            app.git_ref = git_ref;
+        }
+        app.processes = processes;
+        app.deployed_at = get_unix_time() as u64;
+    })
+    .await;
+    if let Err(e) = res {
+        error!("saving the registry failed: {}", e);
+    }
+}
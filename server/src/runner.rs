@@ -1,18 +1,34 @@
+use crate::{
+    error::ServerError, git, github, github::GitHubError, progress::Progress, provider::Provider,
+    registry, secrets, supervisor,
+};
 use async_std::{path::PathBuf, process::Command};
 use pie_lib::{
-    config::{get_repo_config, RepoConfigError},
-    utils::{repo_to_log_file, repo_to_path, string_to_cmd_and_args},
+    api::DeployStatus,
+    config::{
+        get_repo_config, HostKind, RepoConfig, RepoConfigError, RepoConfigTypes, PYTHON_VENV_DIR,
+    },
+    utils::{repo_to_path, string_to_cmd_and_args},
 };
-use std::{io::Error, result::Result};
-use which::which;
+use std::{collections::HashMap, io::Error, result::Result, time::Duration};
 
 #[derive(Debug)]
 pub enum RunnerError {
-    CommandFailed,
+    /// with the command and why it failed
+    CommandFailed(String),
     RepoConfigError(RepoConfigError),
+    SecretsError(Error),
+    PrebuiltError(GitHubError),
+    /// `prebuilt` is set, but the app isn't deployed from github.com
+    PrebuiltNotOnGitHub,
 }
 
-pub async fn exec(cmd: &str, args: Vec<&str>, dir: PathBuf) -> Result<String, Error> {
+pub async fn exec(
+    cmd: &str,
+    args: Vec<&str>,
+    dir: PathBuf,
+    env: &HashMap<String, String>,
+) -> Result<String, Error> {
     debug!(
         "running command: '{} {}' in dir: '{}'",
         &cmd,
@@ -20,82 +36,234 @@ pub async fn exec(cmd: &str, args: Vec<&str>, dir: PathBuf) -> Result<String, Er
         &dir.to_string_lossy()
     );
 
-    let cmd = Command::new(cmd).args(args).current_dir(dir).spawn()?;
-    let out = cmd.output().await?;
-
-    Ok(String::from_utf8(out.stdout).unwrap_or(String::new()))
-}
+    let out = Command::new(cmd)
+        .args(args)
+        .current_dir(dir)
+        .envs(env)
+        .output()
+        .await?;
 
-async fn exec_daemon(cmd: &str, p: PathBuf, repo: &str) -> Result<String, Error> {
-    dbg!();
-
-    let log_file = repo_to_log_file(&repo);
-    if !log_file.exists().await {
-        async_std::fs::write(&log_file, "").await?;
+    if !out.status.success() {
+        return Err(Error::other(format!(
+            "{}: {}",
+            out.status,
+            String::from_utf8_lossy(&out.stderr).trim()
+        )));
     }
 
-    let cmd_args = string_to_cmd_and_args(&cmd);
-    let full_cmd = format!(
-        "daemonize -a -c {dir} -o {logfile} -e {logfile} {cmd} {args}",
-        dir = p.to_str().expect("invalid path"),
-        logfile = log_file.to_str().expect("invalid path"),
-        cmd = which(cmd_args.0)
-            .expect("command not found!")
-            .to_str()
-            .expect("invalid path"),
-        args = cmd_args.1.join(" ")
-    );
-    dbg!(&full_cmd);
-    let full_cmd_args = string_to_cmd_and_args(&full_cmd);
-    exec(full_cmd_args.0, full_cmd_args.1, p).await
+    Ok(String::from_utf8(out.stdout).unwrap_or_default())
 }
 
-async fn run_repo_cmd(cmd: &str, repo: &str, daemonize: bool) -> Result<String, RunnerError> {
+async fn run_repo_cmd(
+    cmd: &str,
+    repo: &str,
+    env: &HashMap<String, String>,
+) -> Result<String, RunnerError> {
     let p = repo_to_path(repo);
-    let cmd_res = if daemonize {
-        exec_daemon(cmd, p, &repo).await
-    } else {
-        let cmd_args = string_to_cmd_and_args(cmd);
-        exec(cmd_args.0, cmd_args.1, p).await
-    };
+    let cmd_args = string_to_cmd_and_args(cmd);
+    let cmd_res = exec(cmd_args.0, cmd_args.1, p, env).await;
 
-    if cmd_res.is_ok() {
-        println!("{}", cmd_res.as_ref().unwrap());
-    } else {
-        println!(
-            "command falied:\n{:?}\n{:?}",
-            cmd,
-            cmd_res.as_ref().err().unwrap()
-        );
+    match &cmd_res {
+        Ok(out) => debug!("{}", out),
+        Err(e) => error!("command falied:\n{:?}\n{:?}", cmd, e),
     }
 
     match cmd_res {
         Ok(x) => Ok(x),
-        Err(_) => Err(RunnerError::CommandFailed),
+        Err(e) => Err(RunnerError::CommandFailed(format!(
+            "`{}` failed: {}",
+            cmd, e
+        ))),
     }
 }
 
-pub async fn run(repo: &str) -> Result<(), RunnerError> {
+/// stop the running app, then install, build and start it
+pub async fn run(repo: &str, progress: &Progress) -> Result<(), RunnerError> {
+    let repo_config = build(repo, progress).await?;
+    progress.send("starting the processes").await;
+    start(repo, &repo_config).await
+}
+
+/// stop the running app, then install and build it without starting it
+pub async fn build(repo: &str, progress: &Progress) -> Result<RepoConfig, RunnerError> {
     let repo_path = repo_to_path(repo);
-    let repo_config_res = get_repo_config(repo_path.clone()).await;
+    let repo_config = get_repo_config(repo_path.clone())
+        .await
+        .map_err(RunnerError::RepoConfigError)?;
+    debug!("running repo with config: {:?}", &repo_config);
 
-    if repo_config_res.is_err() {
-        return Err(RunnerError::RepoConfigError(repo_config_res.err().unwrap()));
-    };
+    progress.send("stopping the running app").await;
+    stop(repo, &repo_config).await?;
 
-    let repo_config = repo_config_res.ok().unwrap();
-    debug!("running repo with config: {:?}", &repo_config);
+    let env = get_env(repo, &repo_config).await?;
+
+    if repo_config._type == Some(RepoConfigTypes::Python)
+        && !repo_path.join(PYTHON_VENV_DIR).is_dir().await
+    {
+        progress.send("creating the virtualenv").await;
+        run_repo_cmd(
+            &format!("python3 -m venv {}", PYTHON_VENV_DIR),
+            repo,
+            &HashMap::new(),
+        )
+        .await?;
+    }
+
+    if let Some(install_command) = &repo_config.install_command {
+        progress.send("running the install command").await;
+        run_repo_cmd(install_command, repo, &env).await?;
+    }
+    if let Some(asset) = &repo_config.prebuilt {
+        progress.send("downloading the prebuilt asset").await;
+        download_prebuilt(repo, &repo_config, asset).await?;
+    } else if let Some(build_command) = &repo_config.build_command {
+        progress.send("running the build command").await;
+        run_repo_cmd(build_command, repo, &env).await?;
+    }
+    Ok(repo_config)
+}
+
+/// start the apps of the registry when the server boots, except the stopped ones. an app whose
+/// last deploy succeeded and whose commit didn't change is only started, the others are deployed again
+pub async fn resume_apps() {
+    for app in registry::list().await {
+        let repo = &app.repo;
+        let _lock = registry::lock(repo).await;
+        if app.stopped {
+            info!("not starting {}, it was stopped", repo);
+            continue;
+        }
+        let repo_config = match get_repo_config(repo_to_path(repo)).await {
+            Ok(c) => c,
+            Err(e) => {
+                warn!("not starting {}, its config is invalid: {:?}", repo, e);
+                continue;
+            }
+        };
+        if !repo_config.autostart.unwrap_or(true) {
+            info!("not starting {}, autostart is off", repo);
+            continue;
+        }
+
+        let res = if app.status == DeployStatus::Deployed && app.commit == git::head_commit(repo) {
+            info!("starting {}", repo);
+            start(repo, &repo_config).await.map_err(ServerError::from)
+        } else {
+            info!("deploying {} again", repo);
+            registry::deploying(repo, |_| ()).await;
+            let res = run(repo, &Progress::new(repo, None))
+                .await
+                .map_err(ServerError::from);
+            registry::deployed(repo, res.as_ref().err().map(|e| e.to_string())).await;
+            res
+        };
+        if let Err(e) = res {
+            error!("starting {} failed: {}", repo, e);
+        }
+    }
+}
 
-    if repo_config.install_command.is_some() {
-        println!("running install command");
-        run_repo_cmd(&repo_config.install_command.unwrap(), &repo, false).await?;
+/// download a release asset in place of the program of the start command, instead of building it
+async fn download_prebuilt(
+    repo: &str,
+    repo_config: &RepoConfig,
+    asset: &str,
+) -> Result<(), RunnerError> {
+    // the asset comes from the repo's releases on github.com, so an app with the same name on
+    // another host, or an upload, would download and run the release of an unrelated repo
+    let on_github = registry::get(repo)
+        .await
+        .and_then(|app| app.url)
+        .and_then(|url| Provider::from_url(&url))
+        .is_some_and(|p| p.kind == HostKind::GitHub);
+    if !on_github {
+        return Err(RunnerError::PrebuiltNotOnGitHub);
     }
-    if repo_config.build_command.is_some() {
-        println!("running build command");
-        run_repo_cmd(&repo_config.build_command.unwrap(), &repo, false).await?;
+
+    let asset = asset
+        .replace("{arch}", std::env::consts::ARCH)
+        .replace("{os}", std::env::consts::OS);
+    let program = string_to_cmd_and_args(&repo_config.start_command).0;
+    let dest = repo_to_path(repo).join(program);
+
+    match github::download_release_asset(repo, &asset, dest).await {
+        Ok(_) => Ok(()),
+        Err(e) => {
+            error!("downloading {} failed:\n{:?}", &asset, e);
+            Err(RunnerError::PrebuiltError(e))
+        }
+    }
+}
+
+/// the environment of a repo's commands: the `[env]` table of pie.toml, overridden by the repo's secrets
+async fn get_env(
+    repo: &str,
+    repo_config: &RepoConfig,
+) -> Result<HashMap<String, String>, RunnerError> {
+    let secrets = match secrets::get(repo).await {
+        Ok(s) => s,
+        Err(e) => return Err(RunnerError::SecretsError(e)),
+    };
+
+    Ok(repo_config.env.clone().into_iter().chain(secrets).collect())
+}
+
+/// start every process of a repo under the supervisor, restarting them as configured in its pie.toml
+pub async fn start(repo: &str, repo_config: &RepoConfig) -> Result<(), RunnerError> {
+    let env = get_env(repo, repo_config).await?;
+    let restart = supervisor::Restart {
+        policy: repo_config
+            .restart
+            .unwrap_or(supervisor::DEFAULT_RESTART_POLICY),
+        max_retries: repo_config
+            .restart_max_retries
+            .unwrap_or(supervisor::DEFAULT_RESTART_MAX_RETRIES),
+        backoff: Duration::from_secs(
+            repo_config
+                .restart_backoff
+                .unwrap_or(supervisor::DEFAULT_RESTART_BACKOFF),
+        ),
+    };
+
+    for (name, cmd) in &repo_config.processes {
+        match supervisor::spawn(repo, name, cmd, repo_to_path(repo), env.clone(), restart).await {
+            Ok(pid) => info!("started {} {} with pid {}", repo, name, pid),
+            Err(e) => {
+                error!("command falied:\n{:?}\n{:?}", cmd, e);
+                return Err(RunnerError::CommandFailed(format!(
+                    "starting {} with `{}` failed: {}",
+                    name, cmd, e
+                )));
+            }
+        }
     }
-    println!("running start command");
-    run_repo_cmd(&repo_config.start_command, &repo, true).await?;
 
     Ok(())
 }
+
+/// stop the running app of a repo, as configured in its pie.toml
+pub async fn stop(repo: &str, repo_config: &RepoConfig) -> Result<(), RunnerError> {
+    let signal = match &repo_config.stop_signal {
+        Some(name) => supervisor::parse_signal(name).unwrap_or_else(|| {
+            warn!("unknown stop signal {:?}, using SIGTERM", name);
+            supervisor::DEFAULT_STOP_SIGNAL
+        }),
+        None => supervisor::DEFAULT_STOP_SIGNAL,
+    };
+    let grace_period = Duration::from_secs(
+        repo_config
+            .stop_grace_period
+            .unwrap_or(supervisor::DEFAULT_STOP_GRACE_PERIOD),
+    );
+
+    match supervisor::stop(repo, signal, grace_period).await {
+        Ok(_) => Ok(()),
+        Err(e) => {
+            error!("stopping {} failed:\n{:?}", repo, e);
+            Err(RunnerError::CommandFailed(format!(
+                "stopping the running app failed: {}",
+                e
+            )))
+        }
+    }
+}
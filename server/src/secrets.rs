@@ -0,0 +1,108 @@
+use async_std::{
+    fs::{self, OpenOptions},
+    os::unix::fs::OpenOptionsExt,
+    path::{Path, PathBuf},
+    prelude::*,
+};
+use pie_lib::utils::repo_to_secrets_file;
+use std::{
+    collections::{BTreeMap, HashMap},
+    io::{Error, ErrorKind},
+};
+
+// secrets are kept out of the repo, in a toml file per repo that only the server can read
+
+pub async fn get(repo: &str) -> Result<HashMap<String, String>, Error> {
+    let file = match fs::read_to_string(repo_to_secrets_file(repo)).await {
+        Ok(f) => f,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(HashMap::new()),
+        Err(e) => return Err(e),
+    };
+
+    toml::from_str(&file).map_err(|e| Error::new(ErrorKind::InvalidData, e))
+}
+
+async fn save(repo: &str, secrets: HashMap<String, String>) -> Result<(), Error> {
+    let path = repo_to_secrets_file(repo);
+    // sorted, so the file doesn't get shuffled on every write
+    let secrets = secrets.into_iter().collect::<BTreeMap<_, _>>();
+    let file = toml::to_string(&secrets).map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+
+    write_private_file(&path, file).await
+}
+
+/// write a file only the server can read, like secrets and keys. it's created that way from the
+/// start, and renamed over the old one at the end, so a crash can't leave it half-written
+pub async fn write_private_file(path: &Path, contents: impl AsRef<[u8]>) -> Result<(), Error> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let tmp = PathBuf::from(tmp);
+
+    let mut f = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(&tmp)
+        .await?;
+    f.write_all(contents.as_ref()).await?;
+    f.sync_all().await?;
+    fs::rename(&tmp, path).await
+}
+
+/// secrets are passed as env variables, so their keys have to be valid names of one
+pub fn is_valid_key(key: &str) -> bool {
+    let mut chars = key.chars();
+    chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+pub async fn set(repo: &str, key: &str, value: &str) -> Result<(), Error> {
+    let mut secrets = get(repo).await?;
+    secrets.insert(key.to_string(), value.to_string());
+    save(repo, secrets).await
+}
+
+/// returns whether the secret existed
+pub async fn unset(repo: &str, key: &str) -> Result<bool, Error> {
+    let mut secrets = get(repo).await?;
+    let existed = secrets.remove(key).is_some();
+    if existed {
+        save(repo, secrets).await?;
+    }
+    Ok(existed)
+}
+
+/// hide a secret's value, only leaving the end of long values visible so they can be told apart
+pub fn mask(value: &str) -> String {
+    let chars = value.chars().collect::<Vec<char>>();
+    if chars.len() < 12 {
+        return "********".into();
+    }
+    let end = chars[chars.len() - 4..].iter().collect::<String>();
+    format!("********{}", end)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn masking() {
+        assert_eq!(mask(""), "********");
+        assert_eq!(mask("hunter2"), "********");
+        assert_eq!(mask("ghp_0123456789abcdef"), "********cdef");
+    }
+
+    #[test]
+    fn keys() {
+        assert!(is_valid_key("DATABASE_URL"));
+        assert!(is_valid_key("_token2"));
+        assert!(!is_valid_key(""));
+        assert!(!is_valid_key("2FA"));
+        assert!(!is_valid_key("A=B"));
+        assert!(!is_valid_key("API-KEY"));
+    }
+}
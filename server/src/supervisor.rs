@@ -0,0 +1,463 @@
+use async_std::{
+    future::timeout,
+    os::unix::process::CommandExt,
+    path::PathBuf,
+    process::{Child, Command, Stdio},
+    sync::Mutex,
+    task,
+};
+use pie_lib::{
+    api::{AppStatus, ProcessStatus},
+    config::RestartPolicy,
+    utils::{repo_process_to_log_file, string_to_cmd_and_args},
+};
+use std::{
+    collections::HashMap,
+    fs::OpenOptions,
+    io::{Error, ErrorKind, Write},
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, Instant},
+};
+
+pub const DEFAULT_STOP_SIGNAL: i32 = libc::SIGTERM;
+pub const DEFAULT_STOP_GRACE_PERIOD: u64 = 10;
+pub const DEFAULT_RESTART_POLICY: RestartPolicy = RestartPolicy::OnFailure;
+pub const DEFAULT_RESTART_MAX_RETRIES: u32 = 5;
+pub const DEFAULT_RESTART_BACKOFF: u64 = 1;
+/// the backoff between restarts never grows longer than this
+const MAX_RESTART_BACKOFF: Duration = Duration::from_secs(300);
+/// an app that stayed up for this long gets its retries back
+const RESTART_RESET_AFTER: Duration = Duration::from_secs(60);
+const WATCH_INTERVAL: Duration = Duration::from_secs(1);
+
+lazy_static! {
+    /// every process of every repo, by `(repo, process name)`
+    static ref PROCESSES: Mutex<HashMap<(String, String), Process>> = Mutex::new(HashMap::new());
+}
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+struct Process {
+    /// unique per `spawn` call, so a watcher can tell if its process got replaced
+    id: u64,
+    child: Child,
+    cmd: String,
+    dir: PathBuf,
+    env: HashMap<String, String>,
+    started: Instant,
+    exited: Option<(Option<i32>, Instant)>,
+    restarts: u32,
+    stopped: bool,
+}
+
+impl Process {
+    /// check on the child without blocking, and remember when it exited
+    fn poll(&mut self) {
+        if self.exited.is_some() {
+            return;
+        }
+        if let Ok(Some(status)) = self.child.try_status() {
+            self.exited = Some((status.code(), Instant::now()));
+        }
+    }
+
+    fn status(&mut self) -> ProcessStatus {
+        self.poll();
+
+        match self.exited {
+            None => ProcessStatus::Running {
+                pid: self.child.id(),
+                cmd: self.cmd.clone(),
+                uptime: self.started.elapsed().as_secs(),
+                restarts: self.restarts,
+            },
+            Some((code, exited_at)) => ProcessStatus::Exited {
+                pid: self.child.id(),
+                cmd: self.cmd.clone(),
+                code,
+                uptime: exited_at.duration_since(self.started).as_secs(),
+                restarts: self.restarts,
+            },
+        }
+    }
+}
+
+/// what to do when a supervised process exits on its own
+#[derive(Debug, Clone, Copy)]
+pub struct Restart {
+    pub policy: RestartPolicy,
+    pub max_retries: u32,
+    /// delay before the first restart, doubled for every retry after that
+    pub backoff: Duration,
+}
+
+impl Restart {
+    fn should_restart(&self, code: Option<i32>) -> bool {
+        match self.policy {
+            RestartPolicy::Never => false,
+            RestartPolicy::OnFailure => code != Some(0),
+            RestartPolicy::Always => true,
+        }
+    }
+
+    fn backoff(&self, retry: u32) -> Duration {
+        self.backoff
+            .checked_mul(2u32.saturating_pow(retry))
+            .unwrap_or(MAX_RESTART_BACKOFF)
+            .min(MAX_RESTART_BACKOFF)
+    }
+}
+
+fn key(repo: &str, process: &str) -> (String, String) {
+    (repo.to_string(), process.to_string())
+}
+
+/// write a message from pie itself into the process' log file
+fn log_to_file(repo: &str, process: &str, msg: &str) {
+    let res = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(repo_process_to_log_file(repo, process))
+        .and_then(|mut f| writeln!(f, "[pie] {}", msg));
+
+    if let Err(e) = res {
+        error!(
+            "unable to write to the log file of {} {}: {:?}",
+            &repo, &process, e
+        );
+    }
+}
+
+fn start_child(
+    repo: &str,
+    process: &str,
+    cmd: &str,
+    dir: &PathBuf,
+    env: &HashMap<String, String>,
+) -> Result<Child, Error> {
+    let log_file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(repo_process_to_log_file(repo, process))?;
+
+    let cmd_args = string_to_cmd_and_args(cmd);
+    debug!(
+        "spawning command: '{}' for repo: '{}' process: '{}' in dir: '{}'",
+        &cmd,
+        &repo,
+        &process,
+        &dir.to_string_lossy()
+    );
+
+    let mut command = Command::new(cmd_args.0);
+    command
+        .args(cmd_args.1)
+        .current_dir(dir)
+        .envs(env)
+        .stdin(Stdio::null())
+        .stdout(log_file.try_clone()?)
+        .stderr(log_file);
+    // put the app in its own process group, so stopping it also stops anything it spawned.
+    // it's also stopped when the server exits, or a restarted server would start it a second time.
+    // linux sends that signal when the spawning thread exits, which are the executor's threads here
+    let server = std::process::id() as libc::pid_t;
+    unsafe {
+        command.pre_exec(move || {
+            libc::setpgid(0, 0);
+            libc::prctl(libc::PR_SET_PDEATHSIG, DEFAULT_STOP_SIGNAL);
+            // the server exited before the signal was set up
+            if libc::getppid() != server {
+                return Err(Error::other("the server exited"));
+            }
+            Ok(())
+        });
+    }
+    command.spawn()
+}
+
+/// spawn a process of a repo, with its output going to the process' log file
+pub async fn spawn(
+    repo: &str,
+    process: &str,
+    cmd: &str,
+    dir: PathBuf,
+    env: HashMap<String, String>,
+    restart: Restart,
+) -> Result<u32, Error> {
+    let mut processes = PROCESSES.lock().await;
+    // replacing a running process would leave it running without anything to stop it
+    if let Some(old) = processes.get_mut(&key(repo, process)) {
+        if let ProcessStatus::Running { pid, .. } = old.status() {
+            return Err(Error::new(
+                ErrorKind::AlreadyExists,
+                format!("{} {} is already running with pid {}", repo, process, pid),
+            ));
+        }
+    }
+
+    let child = start_child(repo, process, cmd, &dir, &env)?;
+    let pid = child.id();
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    info!("started {} {} with pid {}", &repo, &process, pid);
+
+    processes.insert(
+        key(repo, process),
+        Process {
+            id,
+            child,
+            cmd: cmd.to_string(),
+            dir,
+            env,
+            started: Instant::now(),
+            exited: None,
+            restarts: 0,
+            stopped: false,
+        },
+    );
+
+    task::spawn(watch(key(repo, process), id, restart));
+
+    Ok(pid)
+}
+
+/// keep an eye on a process, and restart it according to `restart` when it exits
+async fn watch(key: (String, String), id: u64, restart: Restart) {
+    let (repo, name) = (&key.0, &key.1);
+    let mut retries = 0;
+
+    loop {
+        task::sleep(WATCH_INTERVAL).await;
+
+        let (code, uptime) = {
+            let mut processes = PROCESSES.lock().await;
+            let process = match processes.get_mut(&key) {
+                Some(p) if p.id == id && !p.stopped => p,
+                _ => return,
+            };
+            process.poll();
+            match process.exited {
+                Some((code, exited_at)) => (code, exited_at.duration_since(process.started)),
+                None => continue,
+            }
+        };
+
+        if uptime >= RESTART_RESET_AFTER {
+            retries = 0;
+        }
+        if !restart.should_restart(code) {
+            log_to_file(repo, name, &format!("process exited with code {:?}", code));
+            return;
+        }
+        if retries >= restart.max_retries {
+            log_to_file(
+                repo,
+                name,
+                &format!(
+                    "process exited with code {:?}, giving up after {} restarts",
+                    code, retries
+                ),
+            );
+            warn!(
+                "{} {} keeps crashing, not restarting it anymore",
+                repo, name
+            );
+            return;
+        }
+
+        let backoff = restart.backoff(retries);
+        retries += 1;
+        log_to_file(
+            repo,
+            name,
+            &format!(
+                "process exited with code {:?}, restarting in {:?} (attempt {}/{})",
+                code, backoff, retries, restart.max_retries
+            ),
+        );
+        task::sleep(backoff).await;
+
+        let mut processes = PROCESSES.lock().await;
+        let process = match processes.get_mut(&key) {
+            Some(p) if p.id == id && !p.stopped => p,
+            _ => return,
+        };
+        match start_child(repo, name, &process.cmd, &process.dir, &process.env) {
+            Ok(child) => {
+                info!("restarted {} {} with pid {}", repo, name, child.id());
+                process.child = child;
+                process.started = Instant::now();
+                process.exited = None;
+                process.restarts += 1;
+            }
+            Err(e) => {
+                log_to_file(repo, name, &format!("restarting failed: {}", e));
+                error!("restarting {} {} failed: {:?}", repo, name, e);
+            }
+        }
+    }
+}
+
+/// the status of every process of a repo, by process name
+pub async fn status(repo: &str) -> AppStatus {
+    let mut processes = PROCESSES.lock().await;
+    processes
+        .iter_mut()
+        .filter(|((r, _), _)| r == repo)
+        .map(|((_, name), p)| (name.clone(), p.status()))
+        .collect()
+}
+
+/// drop the stopped processes of a repo, so they don't show up in its status anymore
+pub async fn forget(repo: &str) {
+    PROCESSES.lock().await.retain(|(r, _), _| r != repo);
+}
+
+/// parse a signal name from pie.toml, like `SIGINT` or `INT`
+pub fn parse_signal(name: &str) -> Option<i32> {
+    let name = name.trim().to_uppercase();
+    match name.strip_prefix("SIG").unwrap_or(&name) {
+        "HUP" => Some(libc::SIGHUP),
+        "INT" => Some(libc::SIGINT),
+        "QUIT" => Some(libc::SIGQUIT),
+        "KILL" => Some(libc::SIGKILL),
+        "USR1" => Some(libc::SIGUSR1),
+        "USR2" => Some(libc::SIGUSR2),
+        "TERM" => Some(libc::SIGTERM),
+        _ => None,
+    }
+}
+
+fn signal_group(pid: u32, signal: i32) -> Result<(), Error> {
+    match unsafe { libc::kill(-(pid as i32), signal) } {
+        0 => Ok(()),
+        _ => Err(Error::last_os_error()),
+    }
+}
+
+/// stop every process of a repo at once: send them `signal`,
+/// and kill the ones still running after `grace_period`
+pub async fn stop(repo: &str, signal: i32, grace_period: Duration) -> Result<(), Error> {
+    let names = PROCESSES
+        .lock()
+        .await
+        .keys()
+        .filter(|(r, _)| r == repo)
+        .map(|(_, name)| name.clone())
+        .collect::<Vec<String>>();
+
+    let handles = names
+        .into_iter()
+        .map(|name| {
+            let repo = repo.to_string();
+            task::spawn(async move { stop_process(&repo, &name, signal, grace_period).await })
+        })
+        .collect::<Vec<_>>();
+
+    let mut res = Ok(());
+    for handle in handles {
+        if let Err(e) = handle.await {
+            res = Err(e);
+        }
+    }
+    res
+}
+
+async fn stop_process(
+    repo: &str,
+    name: &str,
+    signal: i32,
+    grace_period: Duration,
+) -> Result<(), Error> {
+    let mut process = {
+        let mut processes = PROCESSES.lock().await;
+        let process = match processes.get_mut(&key(repo, name)) {
+            Some(p) => p,
+            None => return Ok(()),
+        };
+
+        // signalled while it's still in the map, so it isn't lost if that fails
+        process.poll();
+        if process.exited.is_none() {
+            let pid = process.child.id();
+            info!(
+                "stopping {} {} (pid {}) with signal {}",
+                &repo, &name, pid, signal
+            );
+            signal_group(pid, signal)?;
+        }
+        process.stopped = true;
+
+        // taken out of the map, so status requests don't wait on the grace period
+        match processes.remove(&key(repo, name)) {
+            Some(p) => p,
+            None => return Ok(()),
+        }
+    };
+
+    let res = wait_for_exit(repo, name, &mut process, grace_period).await;
+
+    // keep the process around, unless a new one got started in the meantime.
+    // one that didn't exit can still be stopped again
+    PROCESSES
+        .lock()
+        .await
+        .entry(key(repo, name))
+        .or_insert(process);
+
+    res
+}
+
+/// wait for a signalled process to exit, killing it after `grace_period`
+async fn wait_for_exit(
+    repo: &str,
+    name: &str,
+    process: &mut Process,
+    grace_period: Duration,
+) -> Result<(), Error> {
+    if process.exited.is_some() {
+        return Ok(());
+    }
+
+    let pid = process.child.id();
+    let status = match timeout(grace_period, process.child.status()).await {
+        Ok(status) => status?,
+        Err(_) => {
+            warn!(
+                "{} {} did not stop after {:?}, killing it",
+                &repo, &name, &grace_period
+            );
+            signal_group(pid, libc::SIGKILL)?;
+            process.child.status().await?
+        }
+    };
+    process.exited = Some((status.code(), Instant::now()));
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn signal_names() {
+        assert_eq!(parse_signal("SIGTERM"), Some(libc::SIGTERM));
+        assert_eq!(parse_signal("int"), Some(libc::SIGINT));
+        assert_eq!(parse_signal(" SIGHUP "), Some(libc::SIGHUP));
+        assert_eq!(parse_signal("SIGWHATEVER"), None);
+    }
+
+    #[test]
+    fn restart_backoff() {
+        let restart = Restart {
+            policy: RestartPolicy::OnFailure,
+            max_retries: 10,
+            backoff: Duration::from_secs(2),
+        };
+        assert_eq!(restart.backoff(0), Duration::from_secs(2));
+        assert_eq!(restart.backoff(3), Duration::from_secs(16));
+        assert_eq!(restart.backoff(100), MAX_RESTART_BACKOFF);
+
+        assert!(restart.should_restart(Some(1)));
+        assert!(restart.should_restart(None));
+        assert!(!restart.should_restart(Some(0)));
+    }
+}
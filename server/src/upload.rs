@@ -0,0 +1,39 @@
+use async_std::{fs, path::PathBuf, task};
+use flate2::read::GzDecoder;
+use pie_lib::utils::repo_to_path;
+use std::io::Error;
+use tar::Archive;
+
+// apps deployed from a local working tree instead of a git remote
+
+/// replace the working tree of a repo with an uploaded tarball.
+/// it's unpacked next to the old tree first, so a broken upload leaves the old one alone
+pub async fn unpack(repo: &str, archive: Vec<u8>) -> Result<(), Error> {
+    let dir = repo_to_path(repo);
+    let tmp = PathBuf::from(format!("{}.upload", dir.display()));
+
+    if tmp.exists().await {
+        fs::remove_dir_all(&tmp).await?;
+    }
+    fs::create_dir_all(&tmp).await?;
+
+    // entries escaping the directory with `..` are skipped by `unpack`
+    let unpack_dir = tmp.clone();
+    let res =
+        task::spawn_blocking(move || Archive::new(GzDecoder::new(&archive[..])).unpack(unpack_dir))
+            .await;
+    if let Err(e) = res {
+        fs::remove_dir_all(&tmp).await?;
+        return Err(e);
+    }
+
+    if dir.exists().await {
+        fs::remove_dir_all(&dir).await?;
+    }
+    fs::rename(&tmp, &dir).await
+}
+
+/// whether the repo was cloned from a git remote, rather than uploaded
+pub async fn is_cloned(repo: &str) -> bool {
+    repo_to_path(repo).join(".git").exists().await
+}
@@ -0,0 +1,311 @@
+use crate::{
+    auth::constant_time_eq, error::ServerError, git, progress::Progress, registry, runner, secrets,
+    PROJECT_DIRS,
+};
+use async_std::{
+    fs,
+    path::{Path, PathBuf},
+};
+use hmac::{Hmac, Mac};
+use pie_lib::{
+    config::{get_repo_config, ForcePushPolicy, HostKind},
+    utils::{generate_key, get_unix_time, repo_to_path, repo_to_webhook_secret_file, url_to_repo},
+};
+use serde::Deserialize;
+use sha2::Sha256;
+use tide::{Request, Response};
+
+// push webhooks from github, gitea and gitlab. each host has its own headers,
+// payloads and way of proving a delivery came from it
+
+/// the secret of a repo's webhook, generating and saving it if it doesn't have one yet.
+/// an existing secret is kept, so a redeploy doesn't break the hook already on the host
+pub async fn get_or_create_secret(repo: &str) -> Result<String, std::io::Error> {
+    if let Some(secret) = get_secret(repo).await.filter(|s| !s.is_empty()) {
+        return Ok(secret);
+    }
+
+    let secret = generate_key();
+    // anyone who can read it could sign pushes that redeploy the app
+    secrets::write_private_file(&repo_to_webhook_secret_file(repo), &secret).await?;
+    Ok(secret)
+}
+
+async fn get_secret(repo: &str) -> Option<String> {
+    fs::read_to_string(repo_to_webhook_secret_file(repo))
+        .await
+        .ok()
+}
+
+/// the fields pie needs from the payloads of every host
+#[derive(Deserialize, Debug)]
+struct Payload {
+    /// github and gitea
+    repository: Option<PayloadRepository>,
+    /// gitlab
+    project: Option<PayloadProject>,
+    /// the pushed ref, like `refs/heads/main`
+    #[serde(rename = "ref")]
+    git_ref: Option<String>,
+}
+#[derive(Deserialize, Debug)]
+struct PayloadRepository {
+    clone_url: String,
+}
+#[derive(Deserialize, Debug)]
+struct PayloadProject {
+    git_http_url: String,
+}
+
+impl Payload {
+    fn repo(&self) -> Option<String> {
+        let url = match (&self.repository, &self.project) {
+            (Some(r), _) => &r.clone_url,
+            (_, Some(p)) => &p.git_http_url,
+            _ => return None,
+        };
+        url_to_repo(url)
+    }
+}
+
+enum Event {
+    Ping,
+    Push,
+    Other(String),
+}
+
+/// which host sent the request, and the event it's about.
+/// gitea sends github's headers too, so it's checked first
+fn event(req: &Request<()>) -> Option<(HostKind, Event)> {
+    let header = |name| req.header(name).map(|h| h.as_str().to_string());
+
+    if let Some(e) = header("X-Gitea-Event") {
+        let event = match e.as_str() {
+            "push" => Event::Push,
+            _ => Event::Other(e),
+        };
+        return Some((HostKind::Gitea, event));
+    }
+    if let Some(e) = header("X-Gitlab-Event") {
+        let event = match e.as_str() {
+            "Push Hook" => Event::Push,
+            _ => Event::Other(e),
+        };
+        return Some((HostKind::GitLab, event));
+    }
+    if let Some(e) = header("X-GitHub-Event") {
+        let event = match e.as_str() {
+            "ping" => Event::Ping,
+            "push" => Event::Push,
+            _ => Event::Other(e),
+        };
+        return Some((HostKind::GitHub, event));
+    }
+
+    None
+}
+
+/// check that a delivery was sent by the host, using the secret we registered the hook with
+fn verify(kind: HostKind, req: &Request<()>, secret: &str, body: &[u8]) -> bool {
+    let header = |name| req.header(name).map(|h| h.as_str());
+
+    match kind {
+        HostKind::GitHub => verify_signature(secret, body, header("X-Hub-Signature-256")),
+        // the same hmac as github, without the `sha256=` prefix
+        HostKind::Gitea => match header("X-Gitea-Signature") {
+            Some(sig) => verify_signature(secret, body, Some(&format!("sha256={}", sig))),
+            None => false,
+        },
+        // gitlab just sends the secret back
+        HostKind::GitLab => header("X-Gitlab-Token")
+            .is_some_and(|t| constant_time_eq(t.as_bytes(), secret.as_bytes())),
+        HostKind::Git => false,
+    }
+}
+
+/// check the `X-Hub-Signature-256` header of a webhook delivery against the repo's secret
+fn verify_signature(secret: &str, body: &[u8], signature: Option<&str>) -> bool {
+    let signature = match signature.and_then(|s| s.strip_prefix("sha256=")) {
+        Some(s) => s,
+        None => return false,
+    };
+    let signature = match hex::decode(signature) {
+        Ok(s) => s,
+        Err(_) => return false,
+    };
+
+    let mut mac = match Hmac::<Sha256>::new_from_slice(secret.as_bytes()) {
+        Ok(m) => m,
+        Err(_) => return false,
+    };
+    mac.update(body);
+    mac.verify_slice(&signature).is_ok()
+}
+
+pub async fn handler(mut req: Request<()>) -> tide::Result {
+    let (kind, event) = match event(&req) {
+        Some(e) => e,
+        None => {
+            return Ok(Response::builder(418)
+                .body("this endpoint is reserved for git host webhooks")
+                .build())
+        }
+    };
+    let req_body = req.body_string().await?;
+
+    let payload = serde_json::from_str::<Payload>(&req_body).ok();
+    let (payload, repo) = match payload.and_then(|p| p.repo().map(|r| (p, r))) {
+        Some(x) => x,
+        None => return Err(ServerError::BadRequest("invalid webhook payload".into()).into()),
+    };
+
+    // only accept deliveries signed with the secret we registered the hook with
+    let secret = match get_secret(&repo).await {
+        Some(s) => s,
+        None => return Err(ServerError::Forbidden("repository is not deployed".into()).into()),
+    };
+    if !verify(kind, &req, &secret, req_body.as_bytes()) {
+        warn!(
+            "rejected {:?} webhook for {} with invalid signature",
+            kind, &repo
+        );
+        return Err(ServerError::Unauthorized("invalid webhook signature".into()).into());
+    }
+
+    // write hook body to a file
+    let mut hooks_folder = PathBuf::from(PROJECT_DIRS.data_local_dir());
+    hooks_folder.push("hooks");
+    if !hooks_folder.is_dir().await {
+        fs::create_dir_all(&hooks_folder)
+            .await
+            .map_err(ServerError::from)?;
+    }
+    let hook_id = get_unix_time().to_string();
+    let event_name = match &event {
+        Event::Ping => "ping",
+        Event::Push => "push",
+        Event::Other(e) => e,
+    };
+    fs::write(
+        Path::join(
+            hooks_folder.as_path(),
+            format!(
+                "{date}-{event}.json",
+                date = hook_id,
+                event = event_name.replace(' ', "-")
+            ),
+        ),
+        &req_body,
+    )
+    .await
+    .map_err(ServerError::from)?;
+
+    match event {
+        Event::Ping => Ok("pong".into()),
+        Event::Push => {
+            // only pushes to the deployed branch redeploy the app
+            let branch = git::tracked_branch(&repo).map_err(ServerError::from)?;
+            let tracked = branch.map(|b| format!("refs/heads/{}", b));
+            if payload.git_ref.is_none() || payload.git_ref != tracked {
+                info!("ignoring push to {:?} of {}", &payload.git_ref, &repo);
+                return Ok(format!("ignored push, {} isn't deployed from it", &repo).into());
+            }
+
+            info!("push to {}", &repo);
+
+            // the policy of the deployed pie.toml, the pulled one might not even parse
+            let on_force_push = get_repo_config(repo_to_path(&repo))
+                .await
+                .ok()
+                .and_then(|c| c.on_force_push)
+                .unwrap_or(ForcePushPolicy::Fail);
+            let _lock = registry::lock(&repo).await;
+            // a stopped app is updated, but stays stopped
+            let start = !registry::get(&repo).await.is_some_and(|a| a.stopped);
+            registry::deploying(&repo, |_| ()).await;
+            let res = redeploy(&repo, on_force_push, start).await;
+            registry::deployed(&repo, res.as_ref().err().map(|e| e.to_string())).await;
+            res?;
+
+            Ok("pull successful".into())
+        }
+        Event::Other(_) => Err(ServerError::NotFound("event not found".into()).into()),
+    }
+}
+
+async fn redeploy(
+    repo: &str,
+    on_force_push: ForcePushPolicy,
+    start: bool,
+) -> Result<(), ServerError> {
+    git::pull(repo, on_force_push).await?;
+    let progress = Progress::new(repo, None);
+    if start {
+        runner::run(repo, &progress).await?;
+    } else {
+        runner::build(repo, &progress).await?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sign(secret: &str, body: &[u8]) -> String {
+        let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).unwrap();
+        mac.update(body);
+        format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+    }
+
+    #[test]
+    fn valid_signature() {
+        let body = br#"{"zen":"Keep it logically awesome."}"#;
+        let sig = sign("hunter2", body);
+        assert!(verify_signature("hunter2", body, Some(&sig)));
+    }
+
+    #[test]
+    fn missing_signature() {
+        let body = br#"{"zen":"Keep it logically awesome."}"#;
+        assert!(!verify_signature("hunter2", body, None));
+        assert!(!verify_signature("hunter2", body, Some("")));
+    }
+
+    #[test]
+    fn tampered_signature() {
+        let body = br#"{"zen":"Keep it logically awesome."}"#;
+        let sig = sign("hunter2", body);
+
+        // body changed after signing
+        assert!(!verify_signature(
+            "hunter2",
+            br#"{"zen":"rm -rf /"}"#,
+            Some(&sig)
+        ));
+        // signed with a different secret
+        assert!(!verify_signature("hunter3", body, Some(&sig)));
+        // signature bytes changed
+        let mut bad = sig.clone();
+        bad.replace_range(bad.len() - 2.., "00");
+        assert!(!verify_signature("hunter2", body, Some(&bad)));
+        // not a sha256 signature
+        let sha1 = sig.replacen("sha256=", "sha1=", 1);
+        assert!(!verify_signature("hunter2", body, Some(&sha1)));
+    }
+
+    #[test]
+    fn payload_repo() {
+        let github = r#"{"ref": "refs/heads/main", "repository": {"full_name": "beni69/pie", "clone_url": "https://github.com/beni69/pie.git"}}"#;
+        let gitlab = r#"{"project": {"git_http_url": "https://gitlab.com/beni69/pie.git"}, "ref": "refs/heads/main"}"#;
+        for body in [github, gitlab] {
+            let payload = serde_json::from_str::<Payload>(body).unwrap();
+            assert_eq!(payload.repo().as_deref(), Some("beni69/pie"));
+            assert_eq!(payload.git_ref.as_deref(), Some("refs/heads/main"));
+        }
+        assert!(serde_json::from_str::<Payload>("{}")
+            .unwrap()
+            .repo()
+            .is_none());
+    }
+}